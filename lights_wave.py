@@ -38,6 +38,35 @@ class LightControl(object):
 
         self.sock.sendto(packet, (self.host, self.port))
 
+    def fade(self, lights, duration, easing=0):
+        """Lights should be a list of tuples like (id, r, g, b) with the
+        RGB values in a [0..1] range. The server fades them over the
+        duration in seconds. Easing: 0 linear, 1 in, 2 out, 3 in-out."""
+
+        packet = bytearray([
+            1,  # API version
+            0,  # Nick tag
+        ])
+        packet.extend(bytearray([ord(char) for char in self.nick]))
+        packet.append(0)  # end nick tag
+
+        to_ubyte = lambda val: int(max(min(val, 1.0), 0.0) * 255)
+        millis = int(max(min(duration, 65.535), 0.0) * 1000)
+
+        for light in lights:
+            packet.extend([
+                3,                      # Fade tag
+                light[0],
+                to_ubyte(light[1]),     # r
+                to_ubyte(light[2]),     # g
+                to_ubyte(light[3]),     # b
+                millis >> 8,            # duration
+                millis & 0xff,
+                easing,
+            ])
+
+        self.sock.sendto(packet, (self.host, self.port))
+
 
 control = LightControl('airzero', 'localhost')
 
@@ -0,0 +1,294 @@
+//! Requests the network APIs can make to the Mapper.
+//!
+//! The HTTP and other servers turn their input into these and hand them
+//! over to the server's event loop, which owns the Mapper.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Effect;
+use crate::mapper::{
+    CuesInfo, EffectInfo, LightChange, LightInfo, Mapper, MapperError, MastersInfo, ScenesInfo,
+};
+
+/// New values for a single light.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LightUpdate {
+    /// Logical light id. Can be left out if the id is known otherwise.
+    #[serde(default)]
+    pub id: Option<u8>,
+    /// Channel values to change: red, green, blue, white, amber or dimmer.
+    #[serde(flatten)]
+    pub change: LightChange,
+}
+
+/// JSON body for setting many lights at once.
+///
+/// This is the JSON equivalent of a v1 message with a nick and light commands.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SetLightsBody {
+    pub nick: Option<String>,
+    pub lights: Vec<LightUpdate>,
+}
+
+/// JSON body for setting master levels. Missing masters are left as they are.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SetMastersBody {
+    #[serde(default)]
+    pub grand_master: Option<u8>,
+    /// Group master levels by group name.
+    #[serde(default)]
+    pub groups: HashMap<String, u8>,
+}
+
+/// JSON body for starting an effect.
+///
+/// Without an `effect`, the named effect from the configuration or the
+/// built-in effect with the same name is started.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StartEffectBody {
+    pub nick: Option<String>,
+    pub name: String,
+    pub effect: Option<Effect>,
+    /// Lights or groups to run on, in order.
+    pub lights: Option<Vec<u8>>,
+}
+
+/// JSON body for recalling a scene.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecallSceneBody {
+    pub name: String,
+    /// Crossfade duration in seconds.
+    #[serde(default)]
+    pub fade: f32,
+}
+
+/// Events pushed to API subscribers.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase", tag = "event")]
+pub enum ApiEvent {
+    /// Some lights changed.
+    Lights { lights: Vec<LightInfo> },
+}
+
+/// Actions the APIs can request.
+///
+/// Requests are serializable so that recordings can keep them.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum ApiRequest {
+    /// Get every light's state.
+    GetLights,
+    /// Get a single light's state.
+    GetLight { id: u8 },
+    /// Set some lights' colors.
+    SetLights {
+        ip: Option<IpAddr>,
+        nick: Option<String>,
+        lights: Vec<LightUpdate>,
+    },
+    /// Get the master levels.
+    GetMasters,
+    /// Set some master levels.
+    SetMasters {
+        grand_master: Option<u8>,
+        groups: HashMap<String, u8>,
+    },
+    /// Get the running effects.
+    GetEffects,
+    /// Start an effect.
+    StartEffect {
+        ip: Option<IpAddr>,
+        nick: Option<String>,
+        name: String,
+        effect: Option<Effect>,
+        lights: Option<Vec<u8>>,
+    },
+    /// Stop an effect, or every effect without a name.
+    StopEffect { name: Option<String> },
+    /// Get the stored scenes and the recalled one.
+    GetScenes,
+    /// Store the current state of the lights as a scene.
+    StoreScene { name: String },
+    /// Delete a stored scene.
+    DeleteScene { name: String },
+    /// Crossfade to a scene.
+    RecallScene { name: String, fade: Duration },
+    /// Let go of the recalled scene.
+    ReleaseScene,
+    /// Get the cue list and where playback is at.
+    GetCues,
+    /// Go to the next cue.
+    GoCue,
+    /// Go back to the previous cue.
+    BackCue,
+    /// Hold the wait before the next cue.
+    PauseCues,
+    /// Continue a held wait.
+    ResumeCues,
+}
+
+impl ApiRequest {
+    /// Does the request change anything, rather than only get something?
+    pub fn is_change(&self) -> bool {
+        !matches!(
+            self,
+            ApiRequest::GetLights
+                | ApiRequest::GetLight { .. }
+                | ApiRequest::GetMasters
+                | ApiRequest::GetEffects
+                | ApiRequest::GetScenes
+                | ApiRequest::GetCues
+        )
+    }
+}
+
+/// Replies to API requests.
+#[derive(Debug)]
+pub enum ApiResponse {
+    Lights(Vec<LightInfo>),
+    Light(LightInfo),
+    Masters(MastersInfo),
+    Effects(Vec<EffectInfo>),
+    Scenes(ScenesInfo),
+    Cues(CuesInfo),
+    /// The request was carried out.
+    Done,
+    /// The request referred to something that doesn't exist.
+    NotFound(String),
+    /// The request couldn't be carried out.
+    Invalid(String),
+}
+
+/// Carry out an API request.
+pub fn handle(mapper: &mut Mapper, request: ApiRequest) -> ApiResponse {
+    match request {
+        ApiRequest::GetLights => ApiResponse::Lights(mapper.lights_info()),
+        ApiRequest::GetLight { id } => match mapper.light_info(id) {
+            Ok(info) => ApiResponse::Light(info),
+            Err(err) => error_response(err),
+        },
+        ApiRequest::SetLights { ip, nick, lights } => {
+            // Check every id first so a bad request doesn't get half applied.
+            for update in &lights {
+                match update.id {
+                    Some(id) => {
+                        if let Err(err) = mapper.light_info(id) {
+                            return error_response(err);
+                        }
+                    }
+                    None => return ApiResponse::Invalid("Missing light id".to_owned()),
+                }
+            }
+            for update in lights {
+                let id = update.id.unwrap();
+                if let Err(err) = mapper.set_light(id, &update.change, ip, nick.clone()) {
+                    return error_response(err);
+                }
+            }
+            ApiResponse::Done
+        }
+        ApiRequest::GetMasters => ApiResponse::Masters(mapper.masters_info()),
+        ApiRequest::SetMasters { grand_master, groups } => {
+            // Same as with lights, check the groups first.
+            if let Some(name) = groups.keys().find(|name| !mapper.has_group(name)) {
+                return error_response(MapperError::UnknownGroup(name.clone()));
+            }
+            for (name, level) in &groups {
+                if let Err(err) = mapper.set_master(Some(name), *level) {
+                    return error_response(err);
+                }
+            }
+            if let Some(level) = grand_master {
+                if let Err(err) = mapper.set_master(None, level) {
+                    return error_response(err);
+                }
+            }
+            ApiResponse::Masters(mapper.masters_info())
+        }
+        ApiRequest::GetEffects => ApiResponse::Effects(mapper.effects_info()),
+        ApiRequest::StartEffect { ip, nick, name, effect, lights } => {
+            match mapper.start_effect(&name, effect, lights, ip, nick) {
+                Ok(()) => ApiResponse::Effects(mapper.effects_info()),
+                Err(err) => error_response(err),
+            }
+        }
+        ApiRequest::StopEffect { name } => {
+            let result = match name {
+                Some(name) => mapper.stop_effect(&name),
+                None => {
+                    mapper.stop_effects();
+                    Ok(())
+                }
+            };
+            match result {
+                Ok(()) => ApiResponse::Effects(mapper.effects_info()),
+                Err(err) => error_response(err),
+            }
+        }
+        ApiRequest::GetScenes => ApiResponse::Scenes(mapper.scenes_info()),
+        ApiRequest::StoreScene { name } => scenes_response(mapper, |m| m.store_scene(&name)),
+        ApiRequest::DeleteScene { name } => scenes_response(mapper, |m| m.delete_scene(&name)),
+        ApiRequest::RecallScene { name, fade } => {
+            scenes_response(mapper, |m| m.recall_scene(&name, fade))
+        }
+        ApiRequest::ReleaseScene => scenes_response(mapper, |m| {
+            m.release_scene();
+            Ok(())
+        }),
+        ApiRequest::GetCues => ApiResponse::Cues(mapper.cues_info()),
+        ApiRequest::GoCue => {
+            mapper.go_cue();
+            ApiResponse::Cues(mapper.cues_info())
+        }
+        ApiRequest::BackCue => {
+            mapper.back_cue();
+            ApiResponse::Cues(mapper.cues_info())
+        }
+        ApiRequest::PauseCues => {
+            mapper.pause_cues();
+            ApiResponse::Cues(mapper.cues_info())
+        }
+        ApiRequest::ResumeCues => {
+            mapper.resume_cues();
+            ApiResponse::Cues(mapper.cues_info())
+        }
+    }
+}
+
+/// Do something with the scenes and reply with their new state.
+fn scenes_response<F>(mapper: &mut Mapper, action: F) -> ApiResponse
+where
+    F: FnOnce(&mut Mapper) -> Result<(), MapperError>,
+{
+    match action(mapper) {
+        Ok(()) => ApiResponse::Scenes(mapper.scenes_info()),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Turn a Mapper error into a response.
+fn error_response(err: MapperError) -> ApiResponse {
+    match err {
+        MapperError::UnknownAddr(id) => ApiResponse::NotFound(format!("Unknown light id {}", id)),
+        MapperError::UnknownGroup(name) => {
+            ApiResponse::NotFound(format!("Unknown light group {}", name))
+        }
+        MapperError::UnknownEffect(name) => {
+            ApiResponse::NotFound(format!("Unknown effect {}", name))
+        }
+        MapperError::UnknownScene(name) => ApiResponse::NotFound(format!("Unknown scene {}", name)),
+        MapperError::InvalidEffect(name) => {
+            ApiResponse::Invalid(format!("Invalid parameters for effect {}", name))
+        }
+        err => ApiResponse::Invalid(format!("{:?}", err)),
+    }
+}
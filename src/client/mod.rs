@@ -83,8 +83,7 @@ impl UdpClient {
 
         let mut ofs = 3 + nick_length;  // api version, nick tag code and terminator, nick bytes
 
-        for i in 0..count {
-            let light = &lights[i];
+        for light in lights {
             buf[ofs] = 1;               // light packet
             buf[ofs + 1] = light.0;     // light number
             buf[ofs + 2] = 0;           // set RGB color
@@ -1,7 +1,9 @@
 //! Configuration file format and loader.
 
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::Path;
+use std::time::Duration;
 use std::{fs, io};
 
 use serde::{Deserialize, Serialize};
@@ -14,8 +16,213 @@ pub struct Root {
     pub server: Server,
     /// Effect devices.
     pub hosts: HashMap<String, Host>,
+    /// Fixture profiles by name.
+    #[serde(default)]
+    pub fixtures: HashMap<String, Fixture>,
     /// Logical device mapping.
     pub mapping: Mapping,
+    /// How the values from different sources are merged.
+    #[serde(default)]
+    pub merge: Merge,
+    /// Screensaver to run when nobody is controlling the lights.
+    pub idle: Option<Idle>,
+    /// Named effects.
+    #[serde(default)]
+    pub effects: HashMap<String, EffectPreset>,
+}
+
+/// Screensaver settings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Idle {
+    /// Seconds without any messages before the screensaver starts.
+    #[serde(default = "default_idle_after")]
+    pub after: f32,
+    pub effect: Effect,
+}
+
+fn default_idle_after() -> f32 {
+    60.0
+}
+
+/// Built-in light effects.
+///
+/// Effects that run on many lights use the lights' order for things like
+/// chases, and `spread` tells how much of a round fits on the lights.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Effect {
+    /// Every light slowly goes around the color circle.
+    #[serde(rename_all = "camelCase")]
+    HueCycle {
+        /// Seconds per round.
+        #[serde(default = "default_hue_cycle_period")]
+        period: f32,
+    },
+    /// A color fades in and out.
+    #[serde(rename_all = "camelCase")]
+    Breathing {
+        #[serde(default = "default_breathing_color")]
+        color: [u8; 3],
+        /// Seconds per breath.
+        #[serde(default = "default_breathing_period")]
+        period: f32,
+    },
+    /// A rainbow runs along the lights.
+    #[serde(rename_all = "camelCase")]
+    RainbowChase {
+        /// Seconds per round.
+        #[serde(default = "default_chase_period")]
+        period: f32,
+        #[serde(default = "default_spread")]
+        spread: f32,
+    },
+    /// Every light flashes at once.
+    #[serde(rename_all = "camelCase")]
+    Strobe {
+        #[serde(default = "default_white")]
+        color: [u8; 3],
+        /// Flashes per second.
+        #[serde(default = "default_strobe_rate")]
+        rate: f32,
+    },
+    /// Lights flash at random and fade out.
+    #[serde(rename_all = "camelCase")]
+    Sparkle {
+        #[serde(default = "default_white")]
+        color: [u8; 3],
+        /// Sparkles per light per second, on average.
+        #[serde(default = "default_sparkle_rate")]
+        rate: f32,
+        /// Seconds for a sparkle to fade out.
+        #[serde(default = "default_sparkle_decay")]
+        decay: f32,
+    },
+    /// Waves of a color run along the lights.
+    #[serde(rename_all = "camelCase")]
+    Wave {
+        #[serde(default = "default_wave_color")]
+        color: [u8; 3],
+        /// Seconds per wave.
+        #[serde(default = "default_chase_period")]
+        period: f32,
+        #[serde(default = "default_spread")]
+        spread: f32,
+    },
+    /// Flickering flames.
+    #[serde(rename_all = "camelCase")]
+    Fire {
+        /// Flickers per second.
+        #[serde(default = "default_fire_speed")]
+        speed: f32,
+    },
+}
+
+impl Effect {
+    /// A built-in effect by its type name, with the default parameters.
+    pub fn builtin(name: &str) -> Option<Effect> {
+        let effect = match name {
+            "hueCycle" => Effect::HueCycle {
+                period: default_hue_cycle_period(),
+            },
+            "breathing" => Effect::Breathing {
+                color: default_breathing_color(),
+                period: default_breathing_period(),
+            },
+            "rainbowChase" => Effect::RainbowChase {
+                period: default_chase_period(),
+                spread: default_spread(),
+            },
+            "strobe" => Effect::Strobe {
+                color: default_white(),
+                rate: default_strobe_rate(),
+            },
+            "sparkle" => Effect::Sparkle {
+                color: default_white(),
+                rate: default_sparkle_rate(),
+                decay: default_sparkle_decay(),
+            },
+            "wave" => Effect::Wave {
+                color: default_wave_color(),
+                period: default_chase_period(),
+                spread: default_spread(),
+            },
+            "fire" => Effect::Fire {
+                speed: default_fire_speed(),
+            },
+            _ => return None,
+        };
+        Some(effect)
+    }
+
+    /// Are the effect's parameters in range?
+    pub fn is_valid(&self) -> bool {
+        let positive = |value: &f32| value.is_finite() && *value > 0.0;
+        match self {
+            Effect::HueCycle { period } | Effect::Breathing { period, .. } => positive(period),
+            Effect::RainbowChase { period, spread } | Effect::Wave { period, spread, .. } => {
+                positive(period) && spread.is_finite()
+            }
+            Effect::Strobe { rate, .. } => positive(rate),
+            Effect::Sparkle { rate, decay, .. } => positive(rate) && positive(decay),
+            Effect::Fire { speed } => positive(speed),
+        }
+    }
+}
+
+fn default_hue_cycle_period() -> f32 {
+    60.0
+}
+
+fn default_breathing_color() -> [u8; 3] {
+    [255, 180, 100]
+}
+
+fn default_breathing_period() -> f32 {
+    8.0
+}
+
+fn default_chase_period() -> f32 {
+    4.0
+}
+
+fn default_spread() -> f32 {
+    1.0
+}
+
+fn default_white() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+fn default_strobe_rate() -> f32 {
+    10.0
+}
+
+fn default_sparkle_rate() -> f32 {
+    1.0
+}
+
+fn default_sparkle_decay() -> f32 {
+    0.3
+}
+
+fn default_wave_color() -> [u8; 3] {
+    [0, 80, 255]
+}
+
+fn default_fire_speed() -> f32 {
+    8.0
+}
+
+/// A named effect that can be started by its name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectPreset {
+    #[serde(flatten)]
+    pub effect: Effect,
+    /// Logical addresses of the lights or groups to run on, in order.
+    /// Defaults to every light but the relays.
+    pub lights: Option<Vec<u8>>,
 }
 
 /// API server configuration.
@@ -28,6 +235,123 @@ pub struct Server {
     pub web_addr: String,
     /// Host address to accept WebSocket connections on.
     pub websocket_addr: String,
+    /// Art-Net input. Accepts ArtDMX packets from lighting desks.
+    pub artnet_input: Option<DmxInput>,
+    /// sACN (E1.31) input.
+    pub sacn_input: Option<DmxInput>,
+    /// How many frames per second to send to the host devices, from 0.1 to 1000.
+    #[serde(default = "default_frame_rate")]
+    pub frame_rate: f32,
+    /// File to keep the scenes in, relative to the working directory.
+    #[serde(default = "default_scenes_file")]
+    pub scenes_file: String,
+    /// Cue list file, relative to the working directory.
+    #[serde(default = "default_cues_file")]
+    pub cues_file: String,
+    /// Directory to record received protocol messages, DMX input and API requests in.
+    pub record_dir: Option<String>,
+}
+
+/// Lowest frame rate accepted, in Hz.
+const MIN_FRAME_RATE: f32 = 0.1;
+/// Highest frame rate accepted, in Hz.
+const MAX_FRAME_RATE: f32 = 1000.0;
+
+fn default_frame_rate() -> f32 {
+    40.0
+}
+
+fn default_scenes_file() -> String {
+    "scenes.yaml".to_owned()
+}
+
+fn default_cues_file() -> String {
+    "cues.yaml".to_owned()
+}
+
+/// DMX-over-network input that drives lights as if it were a DMX node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DmxInput {
+    /// UDP host address to accept packets on.
+    pub addr: String,
+    /// Map of universe -> host. Channels of the universe drive the lights
+    /// on that host through their DMX addresses.
+    ///
+    /// Art-Net universes are 15-bit Port-Addresses (net, subnet and universe).
+    pub universes: HashMap<u16, String>,
+}
+
+/// Merging light values from many sources.
+///
+/// Every source (nick and IP address) has a priority. On each channel,
+/// the value from the source with the highest priority wins. Sources with
+/// the same priority are merged according to the merge mode.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Merge {
+    #[serde(default)]
+    pub mode: MergeMode,
+    /// Priority of sources that don't match any rule.
+    #[serde(default = "default_priority")]
+    pub default_priority: u8,
+    /// Source priorities. The first matching rule is used.
+    #[serde(default)]
+    pub sources: Vec<SourceRule>,
+    /// Seconds of inactivity after which a source's values are dropped,
+    /// revealing whatever is underneath. Without this, a light only drops
+    /// the sources that have been quiet the longest when it has too many.
+    pub release_timeout: Option<f32>,
+}
+
+impl Default for Merge {
+    fn default() -> Merge {
+        Merge {
+            mode: MergeMode::default(),
+            default_priority: default_priority(),
+            sources: vec![],
+            release_timeout: None,
+        }
+    }
+}
+
+fn default_priority() -> u8 {
+    100
+}
+
+/// How values from sources with the same priority are merged.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MergeMode {
+    /// Latest takes precedence.
+    #[default]
+    Ltp,
+    /// Highest takes precedence.
+    Htp,
+}
+
+/// Priority for sources with a nick, an IP address or both.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceRule {
+    pub nick: Option<String>,
+    /// IP address or CIDR block like `10.0.0.0/24`.
+    pub ip: Option<String>,
+    pub priority: u8,
+}
+
+/// Parse an IP address or a CIDR block into an address and a prefix length.
+pub fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = match cidr.split_once('/') {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (cidr.parse::<IpAddr>().ok()?, None),
+    };
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    match prefix {
+        Some(prefix) if prefix > max_prefix => None,
+        Some(prefix) => Some((addr, prefix)),
+        None => Some((addr, max_prefix)),
+    }
 }
 
 /// Maps logical addresses to physical devices.
@@ -36,20 +360,239 @@ pub struct Server {
 pub struct Mapping {
     /// Map of logical address -> Light info
     pub lights: HashMap<u8, Light>,
+    /// Named groups of lights with their own master intensity.
+    #[serde(default)]
+    pub groups: HashMap<String, Group>,
+}
+
+/// A named group of lights.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    /// Logical address of the whole group. Light commands sent to it go
+    /// to every light in the group.
+    pub id: Option<u8>,
+    /// Logical addresses of the lights in the group.
+    pub lights: Vec<u8>,
+}
+
+/// Fixture profile: how a light's DMX channels are laid out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Fixture {
+    /// Channels starting from the light's address. Their count is the
+    /// number of channels the fixture uses.
+    pub channels: Vec<FixtureChannel>,
+    /// Calibration for lights using the profile, unless they have their own.
+    #[serde(default)]
+    pub calibration: Option<Calibration>,
+}
+
+/// Output calibration, for making different batches of fixtures look alike.
+///
+/// Channel values go through the gamma curve and the gain, and then get
+/// scaled to the range between `min` and `max`. Zero always stays off.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Calibration {
+    /// Exponent of the gamma curve. 1 leaves values alone.
+    #[serde(default = "default_gamma")]
+    pub gamma: f32,
+    /// Per-channel gain for white balance.
+    #[serde(default)]
+    pub gain: Gain,
+    /// Lowest output of a channel that's on, like where a LED starts to glow evenly.
+    #[serde(default)]
+    pub min: u8,
+    /// Highest output of a channel.
+    #[serde(default = "default_max")]
+    pub max: u8,
+}
+
+impl Default for Calibration {
+    fn default() -> Calibration {
+        Calibration {
+            gamma: default_gamma(),
+            gain: Gain::default(),
+            min: 0,
+            max: default_max(),
+        }
+    }
+}
+
+fn default_gamma() -> f32 {
+    1.0
+}
+
+fn default_max() -> u8 {
+    255
+}
+
+/// Gain of each color channel, from 0 to 1.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Gain {
+    #[serde(default = "default_gain")]
+    pub red: f32,
+    #[serde(default = "default_gain")]
+    pub green: f32,
+    #[serde(default = "default_gain")]
+    pub blue: f32,
+    #[serde(default = "default_gain")]
+    pub white: f32,
+    #[serde(default = "default_gain")]
+    pub amber: f32,
+}
+
+impl Default for Gain {
+    fn default() -> Gain {
+        Gain {
+            red: default_gain(),
+            green: default_gain(),
+            blue: default_gain(),
+            white: default_gain(),
+            amber: default_gain(),
+        }
+    }
+}
+
+fn default_gain() -> f32 {
+    1.0
+}
+
+impl Fixture {
+    /// The layout used by lights of a given type without a fixture profile.
+    ///
+    /// RGB lights get red, green, blue, master dimmer at full and strobe off.
+    pub fn default_for(light_type: LightType) -> Fixture {
+        use FixtureChannel::*;
+        let channels = match light_type {
+            LightType::Rgb => vec![Red, Green, Blue, Fixed(255), Fixed(0)],
+            LightType::Rgbw => vec![Red, Green, Blue, White],
+            LightType::Rgba => vec![Red, Green, Blue, Amber],
+            LightType::Dimmer | LightType::Relay => vec![Dimmer],
+            LightType::MovingHead => vec![
+                Pan, PanFine, Tilt, TiltFine, ColorWheel, Gobo, Shutter, Dimmer,
+            ],
+        };
+        Fixture {
+            channels,
+            calibration: None,
+        }
+    }
+}
+
+/// What a single DMX channel of a fixture controls.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum FixtureChannel {
+    Red,
+    Green,
+    Blue,
+    /// Red, fine (low) byte for 16-bit color.
+    RedFine,
+    /// Green, fine (low) byte for 16-bit color.
+    GreenFine,
+    /// Blue, fine (low) byte for 16-bit color.
+    BlueFine,
+    White,
+    Amber,
+    /// Intensity of a dimmer, a relay or the master dimmer of a color light.
+    Dimmer,
+    /// Pan position, coarse (high) byte.
+    Pan,
+    /// Pan position, fine (low) byte.
+    PanFine,
+    /// Tilt position, coarse (high) byte.
+    Tilt,
+    /// Tilt position, fine (low) byte.
+    TiltFine,
+    ColorWheel,
+    Gobo,
+    Shutter,
+    /// Always set to a given value, like a master dimmer or strobe channel.
+    Fixed(u8),
+    /// Left alone.
+    Unused,
 }
 
 /// Individual light source that can be controlled over DMX or similar bus.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum Light {
-    Rgb {
-        /// Host device to use
-        host: String,
-        /// DMX address to use.
-        address: u16,
-        /// Human-readable name.
-        name: Option<String>,
-    },
+    Rgb(LightSettings),
+    /// RGB with a white channel.
+    Rgbw(LightSettings),
+    /// RGB with an amber channel.
+    Rgba(LightSettings),
+    /// Single-channel dimmer, like a warm-white wash.
+    Dimmer(LightSettings),
+    /// On/off switch, like a fog machine relay.
+    Relay(LightSettings),
+    /// Fixture with pan and tilt, like a moving head. Its channels usually
+    /// need a fixture profile.
+    MovingHead(LightSettings),
+}
+
+/// Settings shared by all types of lights.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LightSettings {
+    /// Host device to use
+    pub host: String,
+    /// DMX address to use.
+    pub address: u16,
+    /// Human-readable name.
+    pub name: Option<String>,
+    /// Fixture profile to use. Defaults to `Fixture::default_for` the light's type.
+    pub fixture: Option<String>,
+    /// Output calibration. Defaults to the fixture profile's.
+    pub calibration: Option<Calibration>,
+}
+
+/// Types of lights, as in the `type` of a `Light`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum LightType {
+    Rgb,
+    Rgbw,
+    Rgba,
+    Dimmer,
+    Relay,
+    MovingHead,
+}
+
+impl LightType {
+    /// Does the light have color channels?
+    pub fn has_color(self) -> bool {
+        !matches!(self, LightType::Dimmer | LightType::Relay)
+    }
+}
+
+impl Light {
+    /// Get the light's type.
+    pub fn light_type(&self) -> LightType {
+        match self {
+            Light::Rgb(_) => LightType::Rgb,
+            Light::Rgbw(_) => LightType::Rgbw,
+            Light::Rgba(_) => LightType::Rgba,
+            Light::Dimmer(_) => LightType::Dimmer,
+            Light::Relay(_) => LightType::Relay,
+            Light::MovingHead(_) => LightType::MovingHead,
+        }
+    }
+
+    /// Get the settings shared by all types of lights.
+    pub fn settings(&self) -> &LightSettings {
+        match self {
+            Light::Rgb(settings)
+            | Light::Rgbw(settings)
+            | Light::Rgba(settings)
+            | Light::Dimmer(settings)
+            | Light::Relay(settings)
+            | Light::MovingHead(settings) => settings,
+        }
+    }
 }
 
 /// Host device configuration.
@@ -60,10 +603,39 @@ pub enum Host {
         /// Path to a serial device.
         path: Option<String>,
     },
+    /// Another effect server. Only red, green and blue are forwarded.
     Proxy {
-        // Target UDP address.
+        /// Target UDP address.
         addr: String,
-    }
+    },
+    #[serde(rename = "artnet")]
+    ArtNet {
+        /// Target UDP address of the node, usually port 6454.
+        /// Can be a broadcast address.
+        addr: String,
+        /// Art-Net net (0-127).
+        #[serde(default)]
+        net: u8,
+        /// Art-Net subnet (0-15).
+        #[serde(default)]
+        subnet: u8,
+        /// Universe within the subnet (0-15).
+        #[serde(default)]
+        universe: u8,
+    },
+    Sacn {
+        /// sACN universe (1-63999).
+        universe: u16,
+        /// Target UDP address. Defaults to the universe's multicast address.
+        addr: Option<String>,
+        /// Stream priority (0-200).
+        priority: Option<u8>,
+        /// Source name shown by receivers.
+        source_name: Option<String>,
+        /// Component identifier as a UUID string. A random one is made up
+        /// on every start if this is missing.
+        cid: Option<String>,
+    },
 }
 
 /// Read configuration from a JSON file.
@@ -96,16 +668,237 @@ fn check_config(root: Root) -> io::Result<Root> {
     let hosts = &root.hosts;
     let lights = &root.mapping.lights;
 
-    for (id, light) in lights {
-        match light {
-            Light::Rgb { host, .. } => {
-                // RGB lights should refer to a valid host.
-                if !hosts.contains_key(host) {
-                    eprintln!("Light {} refers to invalid host: {}", id, host);
+    // Anything outside this range makes the frame thread panic or spin.
+    if !(MIN_FRAME_RATE..=MAX_FRAME_RATE).contains(&root.server.frame_rate) {
+        eprintln!("Invalid frame rate: {}", root.server.frame_rate);
+        return Err(io::Error::from(io::ErrorKind::InvalidData))
+    }
+
+    let inputs = root.server.artnet_input.iter().chain(root.server.sacn_input.iter());
+    for input in inputs {
+        for (universe, host) in &input.universes {
+            // DMX inputs should refer to a valid host.
+            if !hosts.contains_key(host) {
+                eprintln!("DMX input universe {} refers to invalid host: {}", universe, host);
+                return Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+        }
+    }
+
+    for (id, host) in hosts {
+        if let Host::ArtNet { net, subnet, universe, .. } = host {
+            if *net > 127 || *subnet > 15 || *universe > 15 {
+                eprintln!("Host {} has an invalid Art-Net port address", id);
+                return Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+        }
+        if let Host::Sacn { universe, priority, cid, .. } = host {
+            if *universe == 0 || *universe > 63999 {
+                eprintln!("Host {} has an invalid sACN universe: {}", id, universe);
+                return Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+            if priority.is_some_and(|priority| priority > 200) {
+                eprintln!("Host {} has an invalid sACN priority", id);
+                return Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+            if let Some(cid) = cid {
+                if crate::host::sacn::parse_cid(cid).is_none() {
+                    eprintln!("Host {} has an invalid sACN CID: {}", id, cid);
                     return Err(io::Error::from(io::ErrorKind::InvalidData))
                 }
             }
         }
     }
+
+    if let Some(idle) = &root.idle {
+        if Duration::try_from_secs_f32(idle.after).is_err() || !idle.effect.is_valid() {
+            eprintln!("Invalid idle screensaver settings");
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+    }
+
+    if let Some(timeout) = root.merge.release_timeout {
+        if Duration::try_from_secs_f32(timeout).map_or(true, |timeout| timeout.is_zero()) {
+            eprintln!("Invalid release timeout: {}", timeout);
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+    }
+
+    for rule in &root.merge.sources {
+        if let Some(ip) = &rule.ip {
+            if parse_cidr(ip).is_none() {
+                eprintln!("Invalid source IP address: {}", ip);
+                return Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+        }
+    }
+
+    let is_light_or_group = |id: &u8| {
+        lights.contains_key(id) || root.mapping.groups.values().any(|group| group.id == Some(*id))
+    };
+    for (name, preset) in &root.effects {
+        if !preset.effect.is_valid() {
+            eprintln!("Effect {} has invalid parameters", name);
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+        if let Some(id) = preset.lights.iter().flatten().find(|id| !is_light_or_group(id)) {
+            eprintln!("Effect {} refers to invalid light: {}", name, id);
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+    }
+
+    let mut group_ids = HashMap::new();
+    for (name, group) in &root.mapping.groups {
+        // Group ids share the logical address space with lights.
+        if let Some(id) = group.id {
+            if lights.contains_key(&id) {
+                eprintln!("Group {} has the same id as a light: {}", name, id);
+                return Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+            if let Some(other) = group_ids.insert(id, name) {
+                eprintln!("Groups {} and {} have the same id: {}", other, name, id);
+                return Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+        }
+        // The grand master goes by the empty name in the protocol.
+        if name.is_empty() {
+            eprintln!("Groups need a name");
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+        if let Some(id) = group.lights.iter().find(|id| !lights.contains_key(id)) {
+            eprintln!("Group {} refers to invalid light: {}", name, id);
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+    }
+
+    for (name, fixture) in &root.fixtures {
+        if let Some(calibration) = &fixture.calibration {
+            if !is_valid_calibration(calibration) {
+                eprintln!("Fixture {} has an invalid calibration", name);
+                return Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+        }
+    }
+
+    for (id, light) in lights {
+        let LightSettings { host, address, fixture, calibration, .. } = light.settings();
+        if calibration.as_ref().is_some_and(|calibration| !is_valid_calibration(calibration)) {
+            eprintln!("Light {} has an invalid calibration", id);
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+        // Lights should refer to a valid host.
+        if !hosts.contains_key(host) {
+            eprintln!("Light {} refers to invalid host: {}", id, host);
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+        // And a valid fixture profile.
+        let channel_count = match fixture {
+            Some(fixture) => match root.fixtures.get(fixture) {
+                Some(fixture) => fixture.channels.len(),
+                None => {
+                    eprintln!("Light {} refers to invalid fixture: {}", id, fixture);
+                    return Err(io::Error::from(io::ErrorKind::InvalidData))
+                }
+            },
+            None => Fixture::default_for(light.light_type()).channels.len(),
+        };
+        // DMX hosts need all of its channels to fit in the universe.
+        let is_dmx = !matches!(hosts[host], Host::Proxy { .. });
+        if is_dmx && (*address == 0 || *address as usize + channel_count > 512) {
+            eprintln!("Light {} has an invalid DMX address: {}", id, address);
+            return Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+    }
     Ok(root)
 }
+
+/// Gamma should be positive, gains between 0 and 1 and the range the right way around.
+fn is_valid_calibration(calibration: &Calibration) -> bool {
+    let Gain { red, green, blue, white, amber } = calibration.gain;
+    let gains = [red, green, blue, white, amber];
+    calibration.gamma.is_finite()
+        && calibration.gamma > 0.0
+        && gains.iter().all(|gain| (0.0..=1.0).contains(gain))
+        && calibration.min <= calibration.max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check a configuration with a frame rate and some sections after
+    /// the `mapping` section.
+    fn config_with(frame_rate: &str, rest: &str) -> io::Result<Root> {
+        let yaml = format!(
+            r#"
+server:
+  udpAddr: "127.0.0.1:0"
+  webAddr: "127.0.0.1:0"
+  websocketAddr: "127.0.0.1:0"
+  frameRate: {}
+hosts: {{}}
+mapping:
+  lights: {{}}
+{}"#,
+            frame_rate, rest
+        );
+        check_config(serde_yaml::from_str(&yaml).unwrap())
+    }
+
+    #[test]
+    fn frame_rate_range() {
+        for frame_rate in ["0.1", "40", "1000"] {
+            assert!(config_with(frame_rate, "").is_ok(), "{}", frame_rate);
+        }
+        for frame_rate in ["0", "-1", "1e-30", "1001", ".inf", ".nan"] {
+            assert!(config_with(frame_rate, "").is_err(), "{}", frame_rate);
+        }
+    }
+
+    #[test]
+    fn release_timeout_range() {
+        let config =
+            |timeout: &str| config_with("40", &format!("merge: {{releaseTimeout: {}}}", timeout));
+        for timeout in ["0.5", "60", "1e6"] {
+            assert!(config(timeout).is_ok(), "{}", timeout);
+        }
+        for timeout in ["0", "-1", "1e-30", "1e30", ".inf", ".nan"] {
+            assert!(config(timeout).is_err(), "{}", timeout);
+        }
+    }
+
+    #[test]
+    fn idle_after_range() {
+        let config = |after: &str| {
+            let idle = format!("idle: {{after: {}, effect: {{type: \"fire\"}}}}", after);
+            config_with("40", &idle)
+        };
+        for after in ["0", "0.5", "3600"] {
+            assert!(config(after).is_ok(), "{}", after);
+        }
+        for after in ["-1", "1e30", ".inf", ".nan"] {
+            assert!(config(after).is_err(), "{}", after);
+        }
+    }
+
+    #[test]
+    fn builtin_effects_have_default_parameters() {
+        let names = [
+            "hueCycle",
+            "breathing",
+            "rainbowChase",
+            "strobe",
+            "sparkle",
+            "wave",
+            "fire",
+        ];
+        for name in names {
+            let builtin = Effect::builtin(name).expect(name);
+            let parsed: Effect =
+                serde_json::from_value(serde_json::json!({ "type": name })).unwrap();
+            assert_eq!(format!("{:?}", builtin), format!("{:?}", parsed));
+        }
+        assert!(Effect::builtin("HueCycle").is_none());
+        assert!(Effect::builtin("unknown").is_none());
+    }
+}
@@ -0,0 +1,181 @@
+//! Art-Net output support.
+
+use std::io;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use super::dmx::DmxUniverse;
+use super::{LightCommand, LightHost};
+
+/// ArtDMX opcode, little-endian on the wire.
+const OP_DMX: u16 = 0x5000;
+/// Art-Net protocol revision.
+const PROTOCOL_VERSION: u16 = 14;
+/// Size of the ArtDMX header before the channel data.
+const HEADER_SIZE: usize = 18;
+/// Nodes expect the data to be resent every few seconds.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// ArtDMX data length. It has to be even, so the 511 channels get padded.
+const DATA_SIZE: usize = 512;
+
+/// The Art-Net host sends ArtDMX packets for one universe to an Art-Net node.
+pub struct ArtNet {
+    /// Socket connected to the node.
+    socket: UdpSocket,
+    /// 15-bit Port-Address: net, subnet and universe.
+    port_address: u16,
+    /// Sequence number of the last packet. Never 0, as that disables sequencing.
+    sequence: u8,
+    /// Channel data.
+    universe: DmxUniverse,
+    /// Packet buffer reused between flushes.
+    packet: Vec<u8>,
+}
+
+impl ArtNet {
+    /// Construct a new Art-Net host sending to `addr` (usually port 6454).
+    pub fn new(addr: &str, net: u8, subnet: u8, universe: u8) -> io::Result<ArtNet> {
+        println!("Art-Net @ {} (net {}, subnet {}, universe {})", addr, net, subnet, universe);
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        // Nodes are often addressed through a broadcast address.
+        socket.set_broadcast(true)?;
+        socket.connect(addr)?;
+
+        Ok(ArtNet {
+            socket,
+            port_address: port_address(net, subnet, universe),
+            sequence: 0,
+            universe: DmxUniverse::new(),
+            packet: Vec::with_capacity(HEADER_SIZE + DATA_SIZE),
+        })
+    }
+}
+
+/// Read the Port-Address and channel data from an ArtDMX packet.
+///
+/// Returns None for other kinds of packets.
+pub fn parse_dmx(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < HEADER_SIZE || &packet[0..8] != b"Art-Net\0" {
+        return None;
+    }
+    if u16::from_le_bytes([packet[8], packet[9]]) != OP_DMX {
+        return None;
+    }
+    let port_address = u16::from_le_bytes([packet[14], packet[15]]) & 0x7fff;
+    let length = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+    let data = packet.get(HEADER_SIZE..HEADER_SIZE + length)?;
+    Some((port_address, data))
+}
+
+/// Combine the parts of an Art-Net Port-Address.
+pub fn port_address(net: u8, subnet: u8, universe: u8) -> u16 {
+    (u16::from(net & 0x7f) << 8) | (u16::from(subnet & 0x0f) << 4) | u16::from(universe & 0x0f)
+}
+
+impl LightHost for ArtNet {
+    fn take_command(&mut self, cmd: &LightCommand) {
+        self.universe.take_command(cmd);
+    }
+
+    /// Send the current buffer as an ArtDMX packet.
+    fn flush(&mut self) -> io::Result<()> {
+        self.sequence = self.sequence.wrapping_add(1).max(1);
+
+        let packet = &mut self.packet;
+        packet.clear();
+        packet.extend_from_slice(b"Art-Net\0");
+        packet.extend_from_slice(&OP_DMX.to_le_bytes());
+        packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        packet.push(self.sequence);
+        packet.push(0); // Physical input port, informational only.
+        packet.extend_from_slice(&self.port_address.to_le_bytes()); // SubUni, Net
+        packet.extend_from_slice(&(DATA_SIZE as u16).to_be_bytes());
+        packet.extend_from_slice(self.universe.channels());
+        packet.resize(HEADER_SIZE + DATA_SIZE, 0);
+
+        self.socket.send(packet)?;
+        Ok(())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(KEEPALIVE_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Art-Net host sending to a local socket standing in for the node.
+    fn host(net: u8, subnet: u8, universe: u8) -> (ArtNet, UdpSocket) {
+        let node = UdpSocket::bind("127.0.0.1:0").unwrap();
+        node.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = node.local_addr().unwrap().to_string();
+        (ArtNet::new(&addr, net, subnet, universe).unwrap(), node)
+    }
+
+    /// Flush the host and receive the packet it sent.
+    fn flush(host: &mut ArtNet, node: &UdpSocket) -> Vec<u8> {
+        host.flush().unwrap();
+        let mut buf = [0; 1024];
+        let len = node.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn dmx_packet() {
+        let (mut host, node) = host(0x12, 0x3, 0x4);
+        host.universe.payload[1] = 0xaa;
+        host.universe.payload[511] = 0xbb;
+        let packet = flush(&mut host, &node);
+
+        assert_eq!(&packet[0..8], b"Art-Net\0");
+        // OpCode little-endian, protocol version big-endian.
+        assert_eq!(&packet[8..12], &[0x00, 0x50, 0, 14]);
+        assert_eq!(packet[12], 1);
+        // SubUni holds the subnet and universe, then comes the net.
+        assert_eq!(&packet[14..16], &[0x34, 0x12]);
+        // 511 channels padded to an even length, big-endian.
+        assert_eq!(&packet[16..18], &[0x02, 0x00]);
+        assert_eq!(packet.len(), HEADER_SIZE + 512);
+        assert_eq!((packet[18], packet[528], packet[529]), (0xaa, 0xbb, 0));
+    }
+
+    #[test]
+    fn port_address_parts() {
+        assert_eq!(port_address(0, 0, 1), 0x0001);
+        assert_eq!(port_address(0x7f, 0xf, 0xf), 0x7fff);
+        // Parts that are too big don't spill into the others.
+        assert_eq!(port_address(0xff, 0x10, 0x10), 0x7f00);
+    }
+
+    #[test]
+    fn sequence_wraps_to_1() {
+        let (mut host, node) = host(0, 0, 0);
+        host.sequence = 254;
+        assert_eq!(flush(&mut host, &node)[12], 255);
+        // 0 would turn sequencing off.
+        assert_eq!(flush(&mut host, &node)[12], 1);
+        assert_eq!(flush(&mut host, &node)[12], 2);
+    }
+
+    #[test]
+    fn parse_own_packets() {
+        let (mut host, node) = host(1, 2, 3);
+        host.universe.payload[1] = 0xaa;
+        let mut packet = flush(&mut host, &node);
+        let (port_address, data) = parse_dmx(&packet).unwrap();
+        assert_eq!((port_address, data.len(), data[0]), (0x0123, 512, 0xaa));
+
+        // The top bit of the Port-Address isn't part of it.
+        packet[15] |= 0x80;
+        assert_eq!(parse_dmx(&packet).unwrap().0, 0x0123);
+        // Data shorter than its length.
+        assert_eq!(parse_dmx(&packet[..HEADER_SIZE + 511]), None);
+        assert_eq!(parse_dmx(&packet[..HEADER_SIZE - 1]), None);
+        // An ArtPoll, not ArtDMX.
+        packet[9] = 0x20;
+        assert_eq!(parse_dmx(&packet), None);
+        assert_eq!(parse_dmx(b"Art-Nyt\0\0\x50\0\x0e\0\0\0\0\0\0"), None);
+    }
+}
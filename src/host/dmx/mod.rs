@@ -0,0 +1,61 @@
+//! DMX universe buffer shared by the DMX-style hosts.
+
+use super::LightCommand;
+use crate::config::FixtureChannel;
+
+/// Raw DMX message data. Slot 0 is the start code, so slot N is DMX channel N.
+pub type DMXPayload = [u8; 512];
+
+/// A single DMX universe's worth of channel data.
+pub struct DmxUniverse {
+    /// Buffer for raw DMX message data.
+    pub payload: DMXPayload,
+}
+
+impl Default for DmxUniverse {
+    fn default() -> DmxUniverse {
+        DmxUniverse::new()
+    }
+}
+
+impl DmxUniverse {
+    pub fn new() -> DmxUniverse {
+        DmxUniverse { payload: [0; 512] }
+    }
+
+    /// Write a single light's control data into the buffer, laid out
+    /// according to its fixture profile.
+    pub fn take_command(&mut self, cmd: &LightCommand) {
+        let offset = cmd.address;
+        if offset == 0 || offset + cmd.channels.len() > self.payload.len() {
+            panic!("Invalid DMX bus offset: {}", offset);
+        }
+        for (slot, channel) in self.payload[offset..].iter_mut().zip(cmd.channels) {
+            match channel {
+                FixtureChannel::Red => *slot = cmd.red,
+                FixtureChannel::Green => *slot = cmd.green,
+                FixtureChannel::Blue => *slot = cmd.blue,
+                FixtureChannel::RedFine => *slot = cmd.red_fine,
+                FixtureChannel::GreenFine => *slot = cmd.green_fine,
+                FixtureChannel::BlueFine => *slot = cmd.blue_fine,
+                FixtureChannel::White => *slot = cmd.white,
+                FixtureChannel::Amber => *slot = cmd.amber,
+                FixtureChannel::Dimmer => *slot = cmd.dimmer,
+                FixtureChannel::Pan => *slot = (cmd.pan >> 8) as u8,
+                FixtureChannel::PanFine => *slot = cmd.pan as u8,
+                FixtureChannel::Tilt => *slot = (cmd.tilt >> 8) as u8,
+                FixtureChannel::TiltFine => *slot = cmd.tilt as u8,
+                FixtureChannel::ColorWheel => *slot = cmd.color_wheel,
+                FixtureChannel::Gobo => *slot = cmd.gobo,
+                FixtureChannel::Shutter => *slot = cmd.shutter,
+                FixtureChannel::Fixed(value) => *slot = *value,
+                FixtureChannel::Unused => {}
+            }
+        }
+    }
+
+    /// Channel data without the start code, starting from DMX channel 1.
+    pub fn channels(&self) -> &[u8] {
+        &self.payload[1..]
+    }
+}
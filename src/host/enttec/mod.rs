@@ -3,17 +3,16 @@
 use serialport;
 use std::io;
 
+use super::dmx::DmxUniverse;
 use super::{LightCommand, LightHost};
 
-type DMXPayload = [u8; 512];
-
 /// The Enttec host passes light commands to an Enttec DMX controller
 /// connected through its USB serial port.
 pub struct Enttec {
     /// Output port.
     port: Option<Box<dyn serialport::SerialPort>>,
     /// Buffer for raw DMX message data..
-    payload: DMXPayload,
+    universe: DmxUniverse,
 }
 
 impl Enttec {
@@ -32,7 +31,7 @@ impl Enttec {
         };
 
         Ok(Enttec {
-            payload: [0; 512],
+            universe: DmxUniverse::new(),
             port,
         })
     }
@@ -45,16 +44,7 @@ impl LightHost for Enttec {
     /// The buffers could then be mixed somewhere else.
     fn take_command(&mut self, cmd: &LightCommand) {
         // println!("take command: {:?}", cmd);
-        let offset = cmd.address;
-        // let offset = index * 5 + 1;
-        if offset > 507 {
-            panic!("Invalid DMX bus offset: {}", offset);
-        }
-        self.payload[offset] = cmd.red;
-        self.payload[offset + 1] = cmd.green;
-        self.payload[offset + 2] = cmd.blue;
-        self.payload[offset + 3] = 255;
-        self.payload[offset + 4] = 0;
+        self.universe.take_command(cmd);
     }
 
     /// Flush current buffer into the bus.
@@ -66,9 +56,9 @@ impl LightHost for Enttec {
             // Send DMX payload with Enttec header
             let mut writer = io::BufWriter::with_capacity(517, port);
             use std::io::Write;
-            writer.write(&[0x7e, 6, 0, 2])?;
-            writer.write(&self.payload)?;
-            writer.write(&[0xe7])?;
+            writer.write_all(&[0x7e, 6, 0, 2])?;
+            writer.write_all(&self.universe.payload)?;
+            writer.write_all(&[0xe7])?;
             writer.flush()?;
             println!("Wrote DMX controller payload");
         }
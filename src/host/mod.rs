@@ -1,10 +1,18 @@
 //! Host devices receive commands and produce physical effects.
 
 use std::io;
+use std::time::Duration;
+
+use crate::config::FixtureChannel;
 
 pub mod proxy;
+pub mod dmx;
 pub mod enttec;
+pub mod artnet;
+pub mod sacn;
 pub use self::enttec::Enttec;
+pub use self::artnet::ArtNet;
+pub use self::sacn::Sacn;
 
 pub use self::proxy::UdpProxy;
 
@@ -16,17 +24,40 @@ pub trait LightHost {
     ///
     /// TODO: Should this do double buffering to allow rollback in case of protocol fails?
     fn flush(&mut self) -> io::Result<()>;
+    /// How often the host should be flushed even if nothing has changed.
+    ///
+    /// Network protocols like sACN need this to keep receivers from timing out.
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
 }
 
-/// Command to set a single light to a given color.
+/// Command to set a single light to a given state.
 #[derive(Debug)]
-pub struct LightCommand {
+pub struct LightCommand<'a> {
     /// Logical light id. Possibly useful for logging or specific implementations.
     pub id: usize,
     /// Address that may mean something to a specific implementation.
     pub address: usize,
+    /// Fixture profile channels, for hosts that lay out DMX-style channels.
+    pub channels: &'a [FixtureChannel],
     pub red: u8,
     pub green: u8,
     pub blue: u8,
+    /// Low bytes of 16-bit color, for fixtures with fine color channels.
+    pub red_fine: u8,
+    pub green_fine: u8,
+    pub blue_fine: u8,
+    pub white: u8,
+    pub amber: u8,
+    /// Intensity of a dimmer or a relay (0 or 255), or the master dimmer of a color light.
+    pub dimmer: u8,
+    /// 16-bit pan position.
+    pub pan: u16,
+    /// 16-bit tilt position.
+    pub tilt: u16,
+    pub color_wheel: u8,
+    pub gobo: u8,
+    pub shutter: u8,
 }
 
@@ -6,6 +6,11 @@ use super::{LightCommand, LightHost};
 use crate::client::{LightParam, UdpClient};
 
 /// The UDP proxy host passes commands to another effect server.
+///
+/// Only red, green and blue are forwarded, as v1 RGB light commands.
+/// Masters, and the dimmer of color lights without a dimmer channel, are
+/// already applied to them. White, amber, fine color bytes, dimmer and
+/// relay intensities, pan, tilt and the beam channels are dropped.
 pub struct UdpProxy {
     client: UdpClient,
     cmds: Vec<LightParam>,
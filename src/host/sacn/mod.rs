@@ -0,0 +1,341 @@
+//! Streaming ACN (E1.31) output support.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use super::dmx::DmxUniverse;
+use super::{LightCommand, LightHost};
+
+/// Default sACN UDP port.
+pub const SACN_PORT: u16 = 5568;
+/// Default stream priority.
+pub const DEFAULT_PRIORITY: u8 = 100;
+/// Receivers consider a source lost after 2.5 seconds of silence.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// ACN packet identifier at the start of every packet.
+const ACN_PACKET_IDENTIFIER: [u8; 12] = *b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+/// Framing layer option: the data is for visualization only.
+const OPTION_PREVIEW_DATA: u8 = 0x80;
+/// Framing layer option: this is the last packet of the stream.
+const OPTION_STREAM_TERMINATED: u8 = 0x40;
+/// Offsets of the root, framing and DMP layers in a data packet.
+const ROOT_LAYER_OFFSET: usize = 16;
+const FRAMING_LAYER_OFFSET: usize = 38;
+const DMP_LAYER_OFFSET: usize = 115;
+/// Size of the source name field.
+const SOURCE_NAME_SIZE: usize = 64;
+
+/// The sACN host streams one universe to a gateway or multicast group.
+pub struct Sacn {
+    /// Socket connected to the destination.
+    socket: UdpSocket,
+    /// Universe number (1-63999).
+    universe: u16,
+    /// Stream priority (0-200).
+    priority: u8,
+    /// Source name, null padded.
+    source_name: [u8; SOURCE_NAME_SIZE],
+    /// Component identifier.
+    cid: [u8; 16],
+    /// Sequence number of the next packet.
+    sequence: u8,
+    /// Channel data.
+    universe_data: DmxUniverse,
+    /// Packet buffer reused between flushes.
+    packet: Vec<u8>,
+}
+
+impl Sacn {
+    /// Construct a new sACN host.
+    ///
+    /// Without an `addr`, packets go to the universe's multicast address.
+    pub fn new(
+        universe: u16,
+        addr: Option<&String>,
+        priority: u8,
+        source_name: &str,
+        cid: [u8; 16],
+    ) -> io::Result<Sacn> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        match addr {
+            Some(addr) => socket.connect(addr)?,
+            None => socket.connect(multicast_addr(universe))?,
+        }
+        println!("sACN @ {} (universe {})", socket.peer_addr()?, universe);
+
+        let mut name = [0; SOURCE_NAME_SIZE];
+        // Leave room for the null terminator.
+        let name_bytes = source_name.as_bytes();
+        let name_len = name_bytes.len().min(SOURCE_NAME_SIZE - 1);
+        name[..name_len].copy_from_slice(&name_bytes[..name_len]);
+
+        Ok(Sacn {
+            socket,
+            universe,
+            priority,
+            source_name: name,
+            cid,
+            sequence: 0,
+            universe_data: DmxUniverse::new(),
+            packet: Vec::with_capacity(DMP_LAYER_OFFSET + 10 + 512),
+        })
+    }
+
+    /// Send the current buffer with the given framing options.
+    fn send(&mut self, options: u8) -> io::Result<()> {
+        // The DMX start code is in slot 0 of the payload.
+        let values = &self.universe_data.payload;
+        let packet = &mut self.packet;
+        packet.clear();
+
+        // Root layer
+        packet.extend_from_slice(&0x0010u16.to_be_bytes()); // Preamble size
+        packet.extend_from_slice(&0u16.to_be_bytes()); // Post-amble size
+        packet.extend_from_slice(&ACN_PACKET_IDENTIFIER);
+        packet.extend_from_slice(&[0, 0]); // Flags and length, filled in below
+        packet.extend_from_slice(&VECTOR_ROOT_E131_DATA.to_be_bytes());
+        packet.extend_from_slice(&self.cid);
+
+        // Framing layer
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&VECTOR_E131_DATA_PACKET.to_be_bytes());
+        packet.extend_from_slice(&self.source_name);
+        packet.push(self.priority);
+        packet.extend_from_slice(&0u16.to_be_bytes()); // Synchronization address
+        packet.push(self.sequence);
+        packet.push(options);
+        packet.extend_from_slice(&self.universe.to_be_bytes());
+
+        // DMP layer
+        packet.extend_from_slice(&[0, 0]);
+        packet.push(VECTOR_DMP_SET_PROPERTY);
+        packet.push(0xa1); // Address type and data type
+        packet.extend_from_slice(&0u16.to_be_bytes()); // First property address
+        packet.extend_from_slice(&1u16.to_be_bytes()); // Address increment
+        packet.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        packet.extend_from_slice(values);
+
+        for offset in &[ROOT_LAYER_OFFSET, FRAMING_LAYER_OFFSET, DMP_LAYER_OFFSET] {
+            let flags_and_length = 0x7000 | (packet.len() - offset) as u16;
+            packet[*offset..*offset + 2].copy_from_slice(&flags_and_length.to_be_bytes());
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.socket.send(packet)?;
+        Ok(())
+    }
+}
+
+/// Multicast address for a universe.
+pub fn multicast_addr(universe: u16) -> SocketAddrV4 {
+    let [high, low] = universe.to_be_bytes();
+    SocketAddrV4::new(Ipv4Addr::new(239, 255, high, low), SACN_PORT)
+}
+
+/// Read the universe and channel data from an E1.31 data packet.
+///
+/// Returns None for other kinds of packets, preview data, terminated
+/// streams and non-zero start codes.
+pub fn parse_data(packet: &[u8]) -> Option<(u16, &[u8])> {
+    let header_size = DMP_LAYER_OFFSET + 10;
+    if packet.len() <= header_size || packet[4..16] != ACN_PACKET_IDENTIFIER {
+        return None;
+    }
+    let read_u16 = |offset: usize| u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+    let read_u32 =
+        |offset: usize| (u32::from(read_u16(offset)) << 16) | u32::from(read_u16(offset + 2));
+    if read_u32(ROOT_LAYER_OFFSET + 2) != VECTOR_ROOT_E131_DATA
+        || read_u32(FRAMING_LAYER_OFFSET + 2) != VECTOR_E131_DATA_PACKET
+        || packet[DMP_LAYER_OFFSET + 2] != VECTOR_DMP_SET_PROPERTY
+    {
+        return None;
+    }
+    let options = packet[FRAMING_LAYER_OFFSET + 74];
+    if options & (OPTION_PREVIEW_DATA | OPTION_STREAM_TERMINATED) != 0 {
+        return None;
+    }
+    let universe = read_u16(FRAMING_LAYER_OFFSET + 75);
+    let count = read_u16(DMP_LAYER_OFFSET + 8) as usize;
+    let values = packet.get(header_size..header_size + count)?;
+    // Only the DMX start code carries channel data.
+    match values.split_first() {
+        Some((0, channels)) => Some((universe, channels)),
+        _ => None,
+    }
+}
+
+/// Parse a component identifier from a UUID string like
+/// `6ba7b810-9dad-11d1-80b4-00c04fd430c8`.
+pub fn parse_cid(cid: &str) -> Option<[u8; 16]> {
+    let digits: Vec<u8> = cid
+        .chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    if digits.len() != 32 {
+        return None;
+    }
+    let mut bytes = [0; 16];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+        *byte = (pair[0] << 4) | pair[1];
+    }
+    Some(bytes)
+}
+
+/// Make up a random version 4 UUID for a component identifier.
+pub fn random_cid() -> [u8; 16] {
+    let mut bytes = [0; 16];
+    for half in bytes.chunks_mut(8) {
+        // RandomState is randomly seeded, which is good enough here.
+        let random = RandomState::new().build_hasher().finish();
+        half.copy_from_slice(&random.to_be_bytes());
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    bytes
+}
+
+impl LightHost for Sacn {
+    fn take_command(&mut self, cmd: &LightCommand) {
+        self.universe_data.take_command(cmd);
+    }
+
+    /// Send the current buffer as an E1.31 data packet.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send(0)
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(KEEPALIVE_INTERVAL)
+    }
+}
+
+impl Drop for Sacn {
+    /// Tell receivers that the stream is over, so they don't have to wait for a timeout.
+    fn drop(&mut self) {
+        // The spec asks for three terminating packets.
+        for _ in 0..3 {
+            if let Err(err) = self.send(OPTION_STREAM_TERMINATED) {
+                eprintln!("Unable to terminate sACN stream: {:?}", err);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CID: &str = "6ba7b810-9dad-11d1-80b4-00c04fd430c8";
+
+    /// An sACN host sending to a local socket standing in for the receiver.
+    fn host(universe: u16) -> (Sacn, UdpSocket) {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = receiver.local_addr().unwrap().to_string();
+        let host = Sacn::new(universe, Some(&addr), 150, "test", parse_cid(CID).unwrap());
+        (host.unwrap(), receiver)
+    }
+
+    fn receive(receiver: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0; 1024];
+        let len = receiver.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn data_packet() {
+        let (mut host, receiver) = host(0x0102);
+        host.universe_data.payload[1] = 0xaa;
+        host.flush().unwrap();
+        let packet = receive(&receiver);
+
+        // The header and 512 property values: the start code and 511 channels.
+        assert_eq!(packet.len(), 637);
+        assert_eq!(&packet[0..4], &[0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(&packet[4..16], b"ASC-E1.17\0\0\0");
+        // Each layer's flags and length run from its start to the end.
+        assert_eq!(&packet[16..18], &(0x7000u16 | (637 - 16)).to_be_bytes());
+        assert_eq!(&packet[38..40], &(0x7000u16 | (637 - 38)).to_be_bytes());
+        assert_eq!(&packet[115..117], &(0x7000u16 | (637 - 115)).to_be_bytes());
+        assert_eq!(&packet[18..22], &[0, 0, 0, 4]);
+        assert_eq!(packet[22..38], parse_cid(CID).unwrap());
+        assert_eq!(&packet[40..44], &[0, 0, 0, 2]);
+        assert_eq!(&packet[44..49], b"test\0");
+        assert_eq!((packet[108], packet[111], packet[112]), (150, 0, 0));
+        assert_eq!(&packet[113..115], &[0x01, 0x02]);
+        assert_eq!(&packet[117..119], &[0x02, 0xa1]);
+        assert_eq!(&packet[123..125], &512u16.to_be_bytes());
+        assert_eq!((packet[125], packet[126], packet[127]), (0, 0xaa, 0));
+    }
+
+    #[test]
+    fn drop_terminates_the_stream() {
+        let (mut host, receiver) = host(1);
+        host.flush().unwrap();
+        assert_eq!(receive(&receiver)[112], 0);
+        drop(host);
+
+        for sequence in 1..4 {
+            let packet = receive(&receiver);
+            assert_eq!(packet[112], OPTION_STREAM_TERMINATED);
+            assert_eq!(packet[111], sequence);
+        }
+    }
+
+    #[test]
+    fn cids() {
+        assert_eq!(
+            parse_cid(CID),
+            Some([
+                0x6b, 0xa7, 0xb8, 0x10, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4,
+                0x30, 0xc8,
+            ])
+        );
+        assert_eq!(
+            parse_cid("6BA7B8109DAD11D180B400C04FD430C8"),
+            parse_cid(CID)
+        );
+        assert_eq!(parse_cid("6ba7b810-9dad-11d1-80b4-00c04fd430c"), None);
+        assert_eq!(parse_cid("6ba7b810-9dad-11d1-80b4-00c04fd430c8a"), None);
+        assert_eq!(parse_cid("6ba7b810-9dad-11d1-80b4-00c04fd430cg"), None);
+        assert_eq!(parse_cid(""), None);
+    }
+
+    #[test]
+    fn parse_own_packets() {
+        let (mut host, receiver) = host(7);
+        host.universe_data.payload[1] = 0xaa;
+        host.flush().unwrap();
+        let packet = receive(&receiver);
+        let (universe, channels) = parse_data(&packet).unwrap();
+        assert_eq!((universe, channels.len(), channels[0]), (7, 511, 0xaa));
+
+        let changed = |offset: usize, value: u8| {
+            let mut packet = packet.clone();
+            packet[offset] = value;
+            packet
+        };
+        // Preview data and terminated streams aren't meant for the lights.
+        assert_eq!(parse_data(&changed(112, OPTION_PREVIEW_DATA)), None);
+        assert_eq!(parse_data(&changed(112, OPTION_STREAM_TERMINATED)), None);
+        // Neither are alternate start codes.
+        assert_eq!(parse_data(&changed(125, 0xdd)), None);
+        // Other root and framing layer vectors.
+        assert_eq!(parse_data(&changed(21, 8)), None);
+        assert_eq!(parse_data(&changed(43, 1)), None);
+        assert_eq!(parse_data(&changed(4, b'B')), None);
+        assert_eq!(parse_data(&packet[..600]), None);
+        assert_eq!(parse_data(&packet[..100]), None);
+    }
+}
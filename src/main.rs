@@ -1,3 +1,4 @@
+pub mod api;
 pub mod client;
 pub mod config;
 pub mod host;
@@ -6,14 +7,27 @@ pub mod parser;
 pub mod server;
 
 fn main() -> std::io::Result<()> {
+    // `--replay <file>` replays a recording of protocol messages.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let replay = match args.as_slice() {
+        [] => None,
+        [flag, path] if flag == "--replay" => Some(std::path::PathBuf::from(path)),
+        _ => {
+            eprintln!("Usage: effectserver2-rs [--replay <file>]");
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        }
+    };
+
     let config_root = config::read_config_yaml("./config.yaml")?;
 
     // println!("{}", serde_yaml::to_string(&config_root).unwrap());
 
-    let cmd_mapper = mapper::Mapper::from_config(&config_root)
-        .map_err(|_| std::io::Error::from(std::io::ErrorKind::Other))?;
+    let cmd_mapper = mapper::Mapper::from_config(&config_root).map_err(|err| {
+        eprintln!("Error setting up mapper: {:?}", err);
+        std::io::Error::from(std::io::ErrorKind::Other)
+    })?;
 
-    server::serve(config_root, cmd_mapper)?;
+    server::serve(config_root, cmd_mapper, replay.as_deref())?;
 
     Ok(())
 }
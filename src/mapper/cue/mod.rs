@@ -0,0 +1,277 @@
+//! Cue lists: timed sequences of scenes and effects played by the server.
+
+use std::time::{Duration, Instant};
+use std::{fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// A step in a cue list.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Cue {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Scene to recall. The recalled scene is left alone without one.
+    #[serde(default)]
+    pub scene: Option<String>,
+    /// Scene crossfade duration in seconds.
+    #[serde(default)]
+    pub fade: f32,
+    /// Effect presets or built-in effects to run instead of the ones
+    /// earlier cues started. Those are left alone without a list, and an
+    /// empty list stops them. Effects started some other way keep running.
+    #[serde(default)]
+    pub effects: Option<Vec<String>>,
+    /// Seconds until the next cue goes by itself. Without a wait, the
+    /// next cue waits for a go.
+    #[serde(default)]
+    pub wait: Option<f32>,
+}
+
+impl Cue {
+    /// Scene crossfade duration. Cue lists are checked for bad times when
+    /// they're loaded, so they don't come up here.
+    pub fn fade_duration(&self) -> Duration {
+        Duration::try_from_secs_f32(self.fade).unwrap_or_default()
+    }
+
+    /// Time until the next cue goes by itself, if it does.
+    pub fn wait_duration(&self) -> Option<Duration> {
+        self.wait
+            .and_then(|wait| Duration::try_from_secs_f32(wait).ok())
+    }
+}
+
+/// Cue list file contents.
+#[derive(Deserialize, Debug)]
+struct CueFile {
+    cues: Vec<Cue>,
+}
+
+/// Read a cue list from a YAML file. A missing file has no cues.
+pub fn load(path: &Path) -> io::Result<Vec<Cue>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let cue_file: CueFile = serde_yaml::from_reader(io::BufReader::new(file))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let valid = |value: f32| Duration::try_from_secs_f32(value).is_ok();
+    if let Some(index) = cue_file
+        .cues
+        .iter()
+        .position(|cue| !valid(cue.fade) || !cue.wait.is_none_or(valid))
+    {
+        let message = format!("Invalid fade or wait time in cue {}", index);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(cue_file.cues)
+}
+
+/// Where playback of a cue list is at.
+pub struct CueList {
+    pub cues: Vec<Cue>,
+    /// Index of the cue that went last.
+    pub current: Option<usize>,
+    /// When the next cue goes by itself.
+    follow_at: Option<Instant>,
+    /// Time left until the next cue, while paused.
+    paused: Option<Duration>,
+}
+
+/// Snapshot of cue list playback for APIs.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CuesInfo {
+    pub cues: Vec<Cue>,
+    pub current: Option<usize>,
+    pub paused: bool,
+    /// Seconds until the next cue goes by itself.
+    pub follow_in: Option<f32>,
+}
+
+impl CueList {
+    pub fn new(cues: Vec<Cue>) -> CueList {
+        CueList {
+            cues,
+            current: None,
+            follow_at: None,
+            paused: None,
+        }
+    }
+
+    /// Move to the next cue and get it, if there is one.
+    pub fn go(&mut self, now: Instant) -> Option<&Cue> {
+        let index = self.current.map_or(0, |current| current + 1);
+        self.jump(index, now)
+    }
+
+    /// Move to the previous cue and get it, if there is one.
+    pub fn back(&mut self, now: Instant) -> Option<&Cue> {
+        let index = self.current?.checked_sub(1)?;
+        self.jump(index, now)
+    }
+
+    fn jump(&mut self, index: usize, now: Instant) -> Option<&Cue> {
+        let cue = match self.cues.get(index) {
+            Some(cue) => cue,
+            None => {
+                // Past the end, there's nothing to follow.
+                self.follow_at = None;
+                self.paused = None;
+                return None;
+            }
+        };
+        let wait = cue.wait_duration();
+        self.current = Some(index);
+        self.paused = None;
+        // A wait too long to tell when it ends never ends.
+        self.follow_at = wait.and_then(|wait| now.checked_add(wait));
+        Some(cue)
+    }
+
+    /// Hold the wait of the current cue.
+    pub fn pause(&mut self, now: Instant) {
+        if let Some(follow_at) = self.follow_at.take() {
+            self.paused = Some(follow_at.saturating_duration_since(now));
+        }
+    }
+
+    /// Continue the wait of the current cue where it was paused.
+    pub fn resume(&mut self, now: Instant) {
+        if let Some(left) = self.paused.take() {
+            self.follow_at = now.checked_add(left);
+        }
+    }
+
+    /// Move to the next cue and get it, if it's time for it to go by itself.
+    pub fn follow(&mut self, now: Instant) -> Option<&Cue> {
+        match self.follow_at {
+            // Count the next wait from when this one ended, so that
+            // sequences don't drift by a frame every cue.
+            Some(follow_at) if now >= follow_at => self.go(follow_at),
+            _ => None,
+        }
+    }
+
+    pub fn info(&self, now: Instant) -> CuesInfo {
+        let follow_in = match (self.follow_at, self.paused) {
+            (Some(follow_at), _) => Some(follow_at.saturating_duration_since(now)),
+            (None, left) => left,
+        };
+        CuesInfo {
+            cues: self.cues.clone(),
+            current: self.current,
+            paused: self.paused.is_some(),
+            follow_in: follow_in.map(|left| left.as_secs_f32()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(name: &str, wait: Option<f32>) -> Cue {
+        Cue {
+            name: Some(name.to_owned()),
+            scene: None,
+            fade: 0.0,
+            effects: None,
+            wait,
+        }
+    }
+
+    fn name(cue: Option<&Cue>) -> Option<&str> {
+        cue.and_then(|cue| cue.name.as_deref())
+    }
+
+    #[test]
+    fn go_and_back() {
+        let now = Instant::now();
+        let mut list = CueList::new(vec![cue("a", None), cue("b", None)]);
+        assert_eq!(name(list.back(now)), None);
+        assert_eq!(name(list.go(now)), Some("a"));
+        assert_eq!(name(list.back(now)), None);
+        assert_eq!(name(list.go(now)), Some("b"));
+        assert_eq!(name(list.back(now)), Some("a"));
+        assert_eq!(name(list.go(now)), Some("b"));
+        assert_eq!(name(list.go(now)), None);
+        assert_eq!(list.current, Some(1));
+    }
+
+    #[test]
+    fn follow_after_wait() {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut list = CueList::new(vec![
+            cue("a", Some(1.0)),
+            cue("b", Some(2.0)),
+            cue("c", None),
+        ]);
+        assert_eq!(name(list.follow(now)), None);
+        list.go(now);
+        assert_eq!(name(list.follow(now)), None);
+        // Late by half a second, but the next wait counts from when
+        // this one ended.
+        assert_eq!(name(list.follow(now + second * 3 / 2)), Some("b"));
+        assert_eq!(name(list.follow(now + second * 5 / 2)), None);
+        assert_eq!(name(list.follow(now + second * 3)), Some("c"));
+        assert_eq!(name(list.follow(now + second * 100)), None);
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut list = CueList::new(vec![cue("a", Some(2.0)), cue("b", None)]);
+        list.go(now);
+        list.pause(now + second);
+        assert!(list.info(now + second).paused);
+        assert_eq!(name(list.follow(now + second * 10)), None);
+        list.resume(now + second * 10);
+        assert_eq!(list.info(now + second * 10).follow_in, Some(1.0));
+        assert_eq!(name(list.follow(now + second * 10)), None);
+        assert_eq!(name(list.follow(now + second * 11)), Some("b"));
+    }
+
+    #[test]
+    fn past_the_end_nothing_follows() {
+        let now = Instant::now();
+        let mut list = CueList::new(vec![cue("a", Some(1.0))]);
+        list.go(now);
+        assert_eq!(name(list.follow(now + Duration::from_secs(1))), None);
+        assert_eq!(list.info(now).follow_in, None);
+        assert_eq!(list.current, Some(0));
+    }
+
+    #[test]
+    fn bad_times_are_rejected() {
+        let path = std::env::temp_dir().join(format!("cues-{}.yaml", std::process::id()));
+        let mut results = vec![];
+        for times in [
+            "fade: 1.5, wait: 0",
+            "fade: -1",
+            "wait: .nan",
+            "fade: 1e30",
+            "wait: .inf",
+        ] {
+            fs::write(&path, format!("cues:\n  - {{{}}}\n", times)).unwrap();
+            results.push(load(&path).is_ok());
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(results, [true, false, false, false, false]);
+    }
+
+    #[test]
+    fn huge_wait_never_follows() {
+        let now = Instant::now();
+        let mut list = CueList::new(vec![cue("a", Some(1e19)), cue("b", None)]);
+        assert_eq!(name(list.go(now)), Some("a"));
+        assert_eq!(
+            name(list.follow(now + Duration::from_secs(1_000_000))),
+            None
+        );
+    }
+}
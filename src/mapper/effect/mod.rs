@@ -0,0 +1,83 @@
+//! Built-in light effects rendered by the server.
+
+use std::f32::consts::PI;
+
+use super::{hsv_to_rgb16, LightChange};
+use crate::config::Effect;
+
+/// Get an effect's output for a light `time` seconds after it started.
+///
+/// `index` is the light's position among the `count` lights the effect runs on.
+pub fn render(effect: &Effect, time: f32, index: usize, count: usize) -> LightChange {
+    // Where the light is along the lights, from 0 to 1.
+    let position = index as f32 / count.max(1) as f32;
+    match effect {
+        Effect::HueCycle { period } => hue((time / period).fract()),
+        Effect::Breathing { color, period } => {
+            let level = (1.0 - (2.0 * PI * time / period).cos()) / 2.0;
+            scaled(*color, level)
+        }
+        Effect::RainbowChase { period, spread } => {
+            hue((time / period - position * spread).rem_euclid(1.0))
+        }
+        Effect::Strobe { color, rate } => {
+            // Half on, half off, so that flashes don't fall between frames.
+            let on = (time * rate).fract() < 0.5;
+            scaled(*color, if on { 1.0 } else { 0.0 })
+        }
+        Effect::Sparkle { color, rate, decay } => {
+            // Every light gets a chance to sparkle once per slot.
+            let slot = (time * rate).floor();
+            let mut level: f32 = 0.0;
+            // A sparkle from the previous slot may still be fading out.
+            for slot in [slot - 1.0, slot] {
+                let seed = noise(index, slot as i64);
+                if seed < 0.5 {
+                    let sparkle_time = (slot + seed * 2.0) / rate;
+                    if time >= sparkle_time {
+                        level = level.max(1.0 - (time - sparkle_time) / decay);
+                    }
+                }
+            }
+            scaled(*color, level.max(0.0))
+        }
+        Effect::Wave { color, period, spread } => {
+            let phase = time / period - position * spread;
+            scaled(*color, (1.0 + (2.0 * PI * phase).sin()) / 2.0)
+        }
+        Effect::Fire { speed } => {
+            // Smoothly interpolated noise, so the flames flicker instead of blinking.
+            let step = time * speed;
+            let start = step.floor();
+            let t = step - start;
+            let t = t * t * (3.0 - 2.0 * t);
+            let from = noise(index, start as i64);
+            let to = noise(index, start as i64 + 1);
+            let flicker = from + (to - from) * t;
+            let color = [255, (40.0 + 100.0 * flicker) as u8, 0];
+            scaled(color, 0.5 + 0.5 * flicker)
+        }
+    }
+}
+
+/// A fully saturated color from a hue from 0 to 1.
+fn hue(hue: f32) -> LightChange {
+    let [red, green, blue] = hsv_to_rgb16((hue * 65536.0) as u16, 255, 255);
+    LightChange::rgb16(red, green, blue)
+}
+
+/// A color scaled to a level from 0 to 1, with 16-bit precision.
+fn scaled(color: [u8; 3], level: f32) -> LightChange {
+    let [red, green, blue] = color.map(|value| (f32::from(value) * 257.0 * level).round() as u16);
+    LightChange::rgb16(red, green, blue)
+}
+
+/// Repeatable noise from 0 to 1 for a light at a step in time.
+fn noise(index: usize, step: i64) -> f32 {
+    // SplitMix64 finalizer.
+    let mut x = (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ step as u64;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    (x >> 40) as f32 / (1u64 << 24) as f32
+}
@@ -0,0 +1,77 @@
+//! Server-side color fades.
+
+use std::time::{Duration, Instant};
+
+use crate::parser::easing;
+
+/// Shape of a fade over time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Get the easing curve for an easing byte in a fade command.
+    pub fn from_id(id: u8) -> Easing {
+        match id {
+            easing::EASE_IN => Easing::EaseIn,
+            easing::EASE_OUT => Easing::EaseOut,
+            easing::EASE_IN_OUT => Easing::EaseInOut,
+            _ => Easing::Linear,
+        }
+    }
+
+    /// Map linear progress in [0, 1] to eased progress in [0, 1].
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => t * (2.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+/// A running fade of a light's 16-bit color.
+#[derive(Debug, Clone, Copy)]
+pub struct Fade {
+    /// Color at the start of the fade.
+    from: [u16; 3],
+    /// Color at the end of the fade.
+    to: [u16; 3],
+    /// When the fade started.
+    start: Instant,
+    duration: Duration,
+    easing: Easing,
+}
+
+impl Fade {
+    /// Start a fade from one color to another now.
+    pub fn new(from: [u16; 3], to: [u16; 3], duration: Duration, easing: Easing) -> Fade {
+        Fade {
+            from,
+            to,
+            start: Instant::now(),
+            duration,
+            easing,
+        }
+    }
+
+    /// Get the color at a point in time, and whether the fade is over.
+    pub fn color_at(&self, now: Instant) -> ([u16; 3], bool) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.duration {
+            return (self.to, true);
+        }
+        let t = self.easing.apply(elapsed.as_secs_f32() / self.duration.as_secs_f32());
+        let mut color = [0; 3];
+        for ((value, from), to) in color.iter_mut().zip(&self.from).zip(&self.to) {
+            let (from, to) = (f32::from(*from), f32::from(*to));
+            *value = (from + (to - from) * t).round() as u16;
+        }
+        (color, false)
+    }
+}
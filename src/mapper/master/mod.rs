@@ -0,0 +1,109 @@
+//! Grand master and group master intensities.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use super::{Light, MapperError, MapperResult};
+use crate::config;
+
+/// A named group of lights with a master intensity.
+struct Group {
+    name: String,
+    /// Logical addresses of the lights in the group.
+    lights: Vec<u8>,
+    master: u8,
+}
+
+/// Master intensities that scale the output of the lights.
+pub struct Masters {
+    /// Scales every light.
+    grand_master: u8,
+    groups: Vec<Group>,
+}
+
+/// Snapshot of the master levels for APIs.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MastersInfo {
+    pub grand_master: u8,
+    /// Group master levels by group name.
+    pub groups: BTreeMap<String, u8>,
+}
+
+impl Masters {
+    /// Set up the configured groups with every master at full.
+    pub fn from_config(groups: &HashMap<String, config::Group>) -> Masters {
+        Masters {
+            grand_master: 255,
+            groups: groups
+                .iter()
+                .map(|(name, group)| Group {
+                    name: name.clone(),
+                    lights: group.lights.clone(),
+                    master: 255,
+                })
+                .collect(),
+        }
+    }
+
+    /// Does a group exist?
+    pub fn has_group(&self, name: &str) -> bool {
+        self.groups.iter().any(|group| group.name == name)
+    }
+
+    /// Set a group's master level, or the grand master's without a group.
+    ///
+    /// The affected lights are marked dirty so they get rendered again.
+    pub fn set(
+        &mut self,
+        group: Option<&str>,
+        level: u8,
+        lights: &mut HashMap<u8, Light>,
+    ) -> MapperResult<()> {
+        match group {
+            None => {
+                self.grand_master = level;
+                for light in lights.values_mut() {
+                    light.dirty = true;
+                }
+            }
+            Some(name) => {
+                let group = self
+                    .groups
+                    .iter_mut()
+                    .find(|group| group.name == name)
+                    .ok_or_else(|| MapperError::UnknownGroup(name.to_owned()))?;
+                group.master = level;
+                for id in &group.lights {
+                    if let Some(light) = lights.get_mut(id) {
+                        light.dirty = true;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the combined master level of a light, from 0 to 1.
+    pub fn level(&self, id: u8) -> f32 {
+        self.groups
+            .iter()
+            .filter(|group| group.lights.contains(&id))
+            .fold(f32::from(self.grand_master) / 255.0, |level, group| {
+                level * f32::from(group.master) / 255.0
+            })
+    }
+
+    /// Get a snapshot of the master levels.
+    pub fn info(&self) -> MastersInfo {
+        MastersInfo {
+            grand_master: self.grand_master,
+            groups: self
+                .groups
+                .iter()
+                .map(|group| (group.name.clone(), group.master))
+                .collect(),
+        }
+    }
+}
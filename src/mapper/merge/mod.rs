@@ -0,0 +1,268 @@
+//! Per-source layers of light values and merging them by priority.
+
+use std::net::IpAddr;
+use std::time::Instant;
+
+use super::fade::Fade;
+use super::{LightChange, SourceKey};
+use crate::config::{self, MergeMode};
+
+/// A source priority rule with its IP address parsed.
+struct Rule {
+    nick: Option<String>,
+    /// Network address and prefix length.
+    ip: Option<(IpAddr, u8)>,
+    priority: u8,
+}
+
+/// Source priority rules from the configuration.
+pub struct SourceRules {
+    rules: Vec<Rule>,
+    default_priority: u8,
+}
+
+impl SourceRules {
+    pub fn from_config(merge: &config::Merge) -> SourceRules {
+        SourceRules {
+            rules: merge
+                .sources
+                .iter()
+                .map(|rule| Rule {
+                    nick: rule.nick.clone(),
+                    ip: rule.ip.as_deref().and_then(config::parse_cidr),
+                    priority: rule.priority,
+                })
+                .collect(),
+            default_priority: merge.default_priority,
+        }
+    }
+
+    /// Get the priority of a source from the first rule that matches it.
+    pub fn priority(&self, ip: Option<IpAddr>, nick: Option<&str>) -> u8 {
+        self.rules
+            .iter()
+            .find(|rule| {
+                let nick_matches = match &rule.nick {
+                    Some(rule_nick) => nick == Some(rule_nick.as_str()),
+                    None => true,
+                };
+                let ip_matches = match (rule.ip, ip) {
+                    (Some((network, prefix)), Some(ip)) => in_network(ip, network, prefix),
+                    (Some(_), None) => false,
+                    (None, _) => true,
+                };
+                nick_matches && ip_matches
+            })
+            .map_or(self.default_priority, |rule| rule.priority)
+    }
+}
+
+/// Is an address in a network?
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// The channels a single source has set on a light.
+pub struct Layer {
+    pub source: SourceKey,
+    pub priority: u8,
+    /// Channel values set by the source so far.
+    pub change: LightChange,
+    /// Color fade in progress.
+    pub fade: Option<Fade>,
+    /// When the source last changed the layer.
+    pub updated: Instant,
+}
+
+impl Layer {
+    pub fn new(source: SourceKey, priority: u8) -> Layer {
+        Layer {
+            source,
+            priority,
+            change: LightChange::default(),
+            fade: None,
+            updated: Instant::now(),
+        }
+    }
+}
+
+/// Merge the layers of a light into the channel values to show.
+///
+/// Higher priority layers cover the lower ones channel by channel, and
+/// layers with the same priority are merged according to the mode.
+pub fn composite(layers: &[Layer], mode: MergeMode) -> LightChange {
+    let mut sorted: Vec<&Layer> = layers.iter().collect();
+    sorted.sort_by_key(|layer| (layer.priority, layer.updated));
+
+    let mut result = LightChange::default();
+    for group in sorted.chunk_by(|a, b| a.priority == b.priority) {
+        let merged = group
+            .iter()
+            .fold(LightChange::default(), |merged, layer| merge(&merged, &layer.change, mode));
+        result = merge(&result, &merged, MergeMode::Ltp);
+    }
+    result
+}
+
+/// Merge two changes channel by channel, `later` being the more recent one.
+pub fn merge(earlier: &LightChange, later: &LightChange, mode: MergeMode) -> LightChange {
+    let pick = |earlier: Option<u8>, later: Option<u8>| match (earlier, later) {
+        (Some(earlier), Some(later)) if mode == MergeMode::Htp => Some(earlier.max(later)),
+        _ => later.or(earlier),
+    };
+    let pick16 = |earlier: Option<u16>, later: Option<u16>| match (earlier, later) {
+        (Some(earlier), Some(later)) if mode == MergeMode::Htp => Some(earlier.max(later)),
+        _ => later.or(earlier),
+    };
+    // 16-bit colors are compared as a whole.
+    let pick_color = |earlier: (Option<u8>, Option<u8>), later: (Option<u8>, Option<u8>)| {
+        if mode == MergeMode::Ltp {
+            return (pick(earlier.0, later.0), pick(earlier.1, later.1));
+        }
+        let value = |(coarse, fine): (Option<u8>, Option<u8>)| {
+            coarse.map(|coarse| u16::from_be_bytes([coarse, fine.unwrap_or(coarse)]))
+        };
+        match pick16(value(earlier), value(later)) {
+            Some(value) => {
+                let [coarse, fine] = value.to_be_bytes();
+                (Some(coarse), Some(fine))
+            }
+            None => (None, None),
+        }
+    };
+    let (red, red_fine) =
+        pick_color((earlier.red, earlier.red_fine), (later.red, later.red_fine));
+    let (green, green_fine) =
+        pick_color((earlier.green, earlier.green_fine), (later.green, later.green_fine));
+    let (blue, blue_fine) =
+        pick_color((earlier.blue, earlier.blue_fine), (later.blue, later.blue_fine));
+
+    LightChange {
+        red,
+        green,
+        blue,
+        red_fine,
+        green_fine,
+        blue_fine,
+        white: pick(earlier.white, later.white),
+        amber: pick(earlier.amber, later.amber),
+        dimmer: pick(earlier.dimmer, later.dimmer),
+        pan: pick16(earlier.pan, later.pan),
+        tilt: pick16(earlier.tilt, later.tilt),
+        color_wheel: pick(earlier.color_wheel, later.color_wheel),
+        gobo: pick(earlier.gobo, later.gobo),
+        shutter: pick(earlier.shutter, later.shutter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn rules(yaml: &str) -> SourceRules {
+        SourceRules::from_config(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    fn layer(nick: &str, priority: u8, age: u64, change: LightChange) -> Layer {
+        let mut layer = Layer::new((None, Some(nick.to_owned())), priority);
+        layer.change = change;
+        layer.updated = Instant::now() - Duration::from_secs(age);
+        layer
+    }
+
+    fn red(value: u8) -> LightChange {
+        LightChange { red: Some(value), ..LightChange::default() }
+    }
+
+    #[test]
+    fn merge_ltp_and_htp() {
+        let earlier = LightChange { red: Some(200), green: Some(10), ..LightChange::default() };
+        let later = LightChange { red: Some(100), blue: Some(50), ..LightChange::default() };
+
+        let ltp = merge(&earlier, &later, MergeMode::Ltp);
+        assert_eq!((ltp.red, ltp.green, ltp.blue), (Some(100), Some(10), Some(50)));
+        let htp = merge(&earlier, &later, MergeMode::Htp);
+        assert_eq!((htp.red, htp.green, htp.blue), (Some(200), Some(10), Some(50)));
+    }
+
+    #[test]
+    fn htp_compares_16_bit_colors_whole() {
+        let earlier = LightChange::rgb16(0x1080, 0, 0);
+        let later = LightChange::rgb16(0x10ff, 0, 0);
+        let htp = merge(&earlier, &later, MergeMode::Htp);
+        assert_eq!((htp.red, htp.red_fine), (Some(0x10), Some(0xff)));
+        let htp = merge(&later, &earlier, MergeMode::Htp);
+        assert_eq!((htp.red, htp.red_fine), (Some(0x10), Some(0xff)));
+    }
+
+    #[test]
+    fn composite_by_priority() {
+        let layers = [
+            layer("high", 200, 10, red(10)),
+            layer("low", 100, 0, LightChange { red: Some(250), green: Some(5), ..red(0) }),
+        ];
+        // The higher priority wins even though it's older, but only on
+        // the channels it has set.
+        for mode in [MergeMode::Ltp, MergeMode::Htp] {
+            let result = composite(&layers, mode);
+            assert_eq!((result.red, result.green), (Some(10), Some(5)));
+        }
+    }
+
+    #[test]
+    fn composite_same_priority_by_mode() {
+        let layers = [layer("new", 100, 0, red(10)), layer("old", 100, 10, red(250))];
+        assert_eq!(composite(&layers, MergeMode::Ltp).red, Some(10));
+        assert_eq!(composite(&layers, MergeMode::Htp).red, Some(250));
+        assert_eq!(composite(&[], MergeMode::Ltp), LightChange::default());
+    }
+
+    #[test]
+    fn source_priorities() {
+        let rules = rules(
+            r#"
+defaultPriority: 50
+sources:
+  - {nick: "vj", ip: "10.0.0.0/24", priority: 250}
+  - {nick: "vj", priority: 200}
+  - {ip: "10.0.0.0/8", priority: 150}
+  - {ip: "fd00::/16", priority: 120}
+  - {ip: "192.168.1.7", priority: 110}
+"#,
+        );
+        let ip = |ip: &str| Some(ip.parse().unwrap());
+        assert_eq!(rules.priority(ip("10.0.0.9"), Some("vj")), 250);
+        assert_eq!(rules.priority(ip("10.0.1.9"), Some("vj")), 200);
+        assert_eq!(rules.priority(None, Some("vj")), 200);
+        assert_eq!(rules.priority(ip("10.255.0.1"), None), 150);
+        assert_eq!(rules.priority(ip("11.0.0.1"), None), 50);
+        assert_eq!(rules.priority(ip("fd00:1::1"), None), 120);
+        assert_eq!(rules.priority(ip("fd01::1"), None), 50);
+        assert_eq!(rules.priority(ip("192.168.1.7"), None), 110);
+        assert_eq!(rules.priority(ip("192.168.1.8"), None), 50);
+        // Rules with an address don't match sources without one.
+        assert_eq!(rules.priority(None, None), 50);
+    }
+
+    #[test]
+    fn networks() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(in_network(ip("1.2.3.4"), ip("0.0.0.0"), 0));
+        assert!(in_network(ip("10.1.2.3"), ip("10.1.0.0"), 16));
+        assert!(!in_network(ip("10.2.2.3"), ip("10.1.0.0"), 16));
+        assert!(in_network(ip("10.1.2.3"), ip("10.1.2.3"), 32));
+        assert!(in_network(ip("::1"), ip("::"), 0));
+        assert!(!in_network(ip("10.1.2.3"), ip("::"), 0));
+    }
+}
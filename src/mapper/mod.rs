@@ -1,29 +1,516 @@
 //! The Mapper maps logical addresses to host device commands.
 
+mod cue;
+mod effect;
+mod fade;
+mod master;
+mod merge;
+mod scene;
+
 use std::net::IpAddr;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
 
-use crate::config::{self, Root};
+use crate::config::{self, FixtureChannel, LightType, MergeMode, Root};
 use crate::host::{self, LightHost, LightCommand};
-use crate::parser::{Command, CommandParser, ParserError};
+use crate::parser::{cue_action, scene_action, Command, CommandParser, ParserError};
+
+use self::cue::{Cue, CueList};
+use self::fade::{Easing, Fade};
+use self::master::Masters;
+use self::merge::{Layer, SourceRules};
+use self::scene::{Recall, Scenes};
+pub use self::cue::CuesInfo;
+pub use self::master::MastersInfo;
+
+/// How long a v2 message source can stay quiet before its sequence
+/// numbers are allowed to start over, like after a client restart.
+const SOURCE_RESET_TIME: Duration = Duration::from_secs(2);
+
+/// Most layers a light keeps, one per source.
+const MAX_LAYERS: usize = 32;
+
+/// Identifies a message source: its IP address and nick.
+type SourceKey = (Option<IpAddr>, Option<String>);
+
+/// Recalled scenes' source. Its priority can be set with the nick `scene`.
+fn scene_source() -> SourceKey {
+    (None, Some("scene".to_owned()))
+}
+
+/// The cue list starts effects as this source. Its priority can be set
+/// with the nick `cue`.
+fn cue_source() -> SourceKey {
+    (None, Some("cue".to_owned()))
+}
+
+/// The screensaver's own source, which goes on top of everything else.
+fn screensaver_source() -> SourceKey {
+    (None, Some("screensaver".to_owned()))
+}
+
+/// Where a v2 message source's sequence numbers and timestamps are at.
+struct SourceOrder {
+    /// Last accepted sequence number.
+    sequence: u32,
+    /// Last accepted timestamp.
+    timestamp: u64,
+    /// When the last message was accepted.
+    seen: Instant,
+}
+
+/// An effect the mapper is running.
+struct RunningEffect {
+    name: String,
+    effect: config::Effect,
+    /// Lights the effect runs on, in order.
+    lights: Vec<u8>,
+    start: Instant,
+    /// Every effect has a source of its own.
+    source: SourceKey,
+    /// Whoever started the effect.
+    started_by: SourceKey,
+    /// Priority of whoever started the effect.
+    priority: u8,
+}
 
-/// A single RGB light's state in the mapper.
-#[allow(dead_code)]
+/// A single light's state in the mapper.
 struct Light {
     /// Name to use for the light.
     name: String,
+    /// Type of the light.
+    light_type: LightType,
     /// Host this light is connected to.
     host_index: usize,
     /// Host-specific address for the light.
     address: usize,
-    /// Last known red intensity.
-    red: u8,
-    /// Last known green intensity.
-    green: u8,
-    /// Last known blue intensity.
-    blue: u8,
-    /// Last IP address that set this.
+    /// Fixture profile of the light.
+    fixture_index: usize,
+    /// Output calibration.
+    calibration: config::Calibration,
+    /// Do masters scale the dimmer channel instead of the colors?
+    master_dimmer: bool,
+    /// Channel values merged from the layers.
+    values: LightValues,
+    /// Channel values set by each source.
+    layers: Vec<Layer>,
+    /// IP address of the source on top.
     ip: Option<IpAddr>,
+    /// Nick of the source on top.
+    nick: Option<String>,
+    /// Has the light changed since the last frame?
+    dirty: bool,
+}
+
+impl Light {
+    /// Change the channels of a source's layer, bringing it on top of the
+    /// layers with the same priority. Nothing happens if the channels
+    /// already have the values and the layer is already on top.
+    ///
+    /// Dimmers and relays only have an intensity, so setting their color
+    /// sets the intensity to the brightest color channel.
+    ///
+    /// Setting any color channel stops the source's fade in progress.
+    fn apply(&mut self, change: &LightChange, source: SourceKey, priority: u8, mode: MergeMode) {
+        let change = layer_change(self.light_type, change);
+        let index = self.layer_index(source, priority);
+        let layer = &mut self.layers[index];
+        if change.red.is_some() || change.green.is_some() || change.blue.is_some() {
+            layer.fade = None;
+        }
+        let merged = merge::merge(&layer.change, &change, MergeMode::Ltp);
+        let unchanged = merged == layer.change;
+        layer.change = merged;
+        // Re-sending the same values still makes them the latest ones.
+        let layer = &self.layers[index];
+        let on_top = self
+            .layers
+            .iter()
+            .all(|other| (other.priority, other.updated) <= (layer.priority, layer.updated));
+        if unchanged && on_top {
+            return;
+        }
+        self.layers[index].updated = Instant::now();
+        self.composite(mode);
+    }
+
+    /// Does a source's layer already have the values of a change?
+    fn has_change(&self, change: &LightChange, source: &SourceKey) -> bool {
+        let change = layer_change(self.light_type, change);
+        self.layers.iter().any(|layer| {
+            layer.source == *source
+                && merge::merge(&layer.change, &change, MergeMode::Ltp) == layer.change
+        })
+    }
+
+    /// Drop a source's layer, if it has one.
+    fn release(&mut self, source: &SourceKey, mode: MergeMode) {
+        let count = self.layers.len();
+        self.layers.retain(|layer| layer.source != *source);
+        if self.layers.len() != count {
+            self.composite(mode);
+        }
+    }
+
+    /// Get the index of a source's layer, adding the layer if the source
+    /// hasn't set anything yet.
+    ///
+    /// Sources are picked by clients, so there's a limit to how many layers
+    /// a light keeps. The layer that has been quiet the longest goes first.
+    fn layer_index(&mut self, source: SourceKey, priority: u8) -> usize {
+        if let Some(index) = self.layers.iter().position(|layer| layer.source == source) {
+            return index;
+        }
+        if self.layers.len() >= MAX_LAYERS {
+            let oldest = self
+                .layers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, layer)| layer.updated)
+                .map(|(index, _)| index);
+            if let Some(oldest) = oldest {
+                self.layers.remove(oldest);
+            }
+        }
+        self.layers.push(Layer::new(source, priority));
+        self.layers.len() - 1
+    }
+
+    /// Merge the layers into the values to show, and remember which source is on top.
+    fn composite(&mut self, mode: MergeMode) {
+        let top = self.layers.iter().max_by_key(|layer| (layer.priority, layer.updated));
+        (self.ip, self.nick) = top.map_or((None, None), |layer| layer.source.clone());
+        let merged = merge::composite(&self.layers, mode);
+        let values = LightValues::initial(self.light_type).with(&merged);
+        if values != self.values {
+            self.values = values;
+            self.dirty = true;
+        }
+    }
+
+    /// Start fading a source's layer from the light's current color to another one.
+    ///
+    /// Dimmers and relays fade their intensity like `apply` sets it, from
+    /// their current intensity.
+    fn start_fade(
+        &mut self,
+        to: [u16; 3],
+        duration: Duration,
+        easing: Easing,
+        source: SourceKey,
+        priority: u8,
+    ) {
+        let from = if self.light_type.has_color() {
+            self.values.color16()
+        } else {
+            [u16::from(self.values.dimmer) * 257; 3]
+        };
+        let index = self.layer_index(source, priority);
+        let layer = &mut self.layers[index];
+        layer.fade = Some(Fade::new(from, to, duration, easing));
+        layer.updated = Instant::now();
+    }
+
+    /// Move fades in progress along to the given time.
+    fn step_fades(&mut self, now: Instant, mode: MergeMode) {
+        let light_type = self.light_type;
+        let mut stepped = false;
+        for layer in &mut self.layers {
+            if let Some(fade) = layer.fade {
+                let ([red, green, blue], done) = fade.color_at(now);
+                let change = layer_change(light_type, &LightChange::rgb16(red, green, blue));
+                layer.change = merge::merge(&layer.change, &change, MergeMode::Ltp);
+                if done {
+                    layer.fade = None;
+                }
+                stepped = true;
+            }
+        }
+        if stepped {
+            self.composite(mode);
+        }
+    }
+
+    /// Get the values to send to the host, scaled by a master level
+    /// from 0 to 1 and calibrated.
+    ///
+    /// Color lights without a dimmer channel have their colors scaled by
+    /// their dimmer value as well, so dimmer commands work on them too.
+    fn output_values(&self, master: f32) -> LightValues {
+        let mut values = self.values;
+        let level = if self.master_dimmer {
+            master
+        } else {
+            master * f32::from(values.dimmer) / 255.0
+        };
+        if level < 1.0 {
+            let scale8 = |value: u8| (f32::from(value) * level).round() as u8;
+            if self.master_dimmer {
+                values.dimmer = scale8(values.dimmer);
+            } else {
+                let scale16 = |value: u16| (f32::from(value) * level).round() as u16;
+                let [red, green, blue] = values.color16().map(scale16);
+                values = values.with(&LightChange::rgb16(red, green, blue));
+                values.white = scale8(values.white);
+                values.amber = scale8(values.amber);
+            }
+        }
+        let calibration = &self.calibration;
+        let gain = &calibration.gain;
+        let [red, green, blue] = values.color16();
+        [values.red, values.red_fine] = calibrate(calibration, gain.red, red).to_be_bytes();
+        [values.green, values.green_fine] =
+            calibrate(calibration, gain.green, green).to_be_bytes();
+        [values.blue, values.blue_fine] = calibrate(calibration, gain.blue, blue).to_be_bytes();
+        values.white = calibrate8(calibration, gain.white, values.white);
+        values.amber = calibrate8(calibration, gain.amber, values.amber);
+        match self.light_type {
+            LightType::Dimmer => values.dimmer = calibrate8(calibration, 1.0, values.dimmer),
+            LightType::Relay => values.dimmer = if values.dimmer >= 128 { 255 } else { 0 },
+            _ => {}
+        }
+        values
+    }
+}
+
+/// A change the way it's kept in a layer of a type of light.
+fn layer_change(light_type: LightType, change: &LightChange) -> LightChange {
+    let mut change = *change;
+    if !light_type.has_color() && change.dimmer.is_none() {
+        let colors = [change.red, change.green, change.blue];
+        change.dimmer = colors.iter().flatten().max().cloned();
+    }
+    // 8-bit colors are stretched to 16 bits, so full is full either way.
+    change.red_fine = change.red_fine.or(change.red);
+    change.green_fine = change.green_fine.or(change.green);
+    change.blue_fine = change.blue_fine.or(change.blue);
+    change
+}
+
+/// Run a 16-bit channel value through a calibration.
+fn calibrate(calibration: &config::Calibration, gain: f32, value: u16) -> u16 {
+    if value == 0 || gain <= 0.0 {
+        return 0;
+    }
+    let level = (f32::from(value) / 65535.0).powf(calibration.gamma) * gain;
+    let min = f32::from(calibration.min) * 257.0;
+    let max = f32::from(calibration.max) * 257.0;
+    (min + (max - min) * level).round() as u16
+}
+
+/// Run an 8-bit channel value through a calibration.
+fn calibrate8(calibration: &config::Calibration, gain: f32, value: u8) -> u8 {
+    let value = calibrate(calibration, gain, u16::from(value) * 257);
+    (f32::from(value) / 257.0).round() as u8
+}
+
+/// Channel values of a light.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LightValues {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    /// Low bytes of 16-bit color.
+    pub red_fine: u8,
+    pub green_fine: u8,
+    pub blue_fine: u8,
+    pub white: u8,
+    pub amber: u8,
+    /// Intensity of a dimmer or a relay, or the master dimmer of a color light.
+    pub dimmer: u8,
+    /// 16-bit pan position.
+    pub pan: u16,
+    /// 16-bit tilt position.
+    pub tilt: u16,
+    pub color_wheel: u8,
+    pub gobo: u8,
+    pub shutter: u8,
+}
+
+impl LightValues {
+    /// Initial values for a type of light: dark, but with color lights'
+    /// master dimmers at full.
+    fn initial(light_type: LightType) -> LightValues {
+        LightValues {
+            dimmer: if light_type.has_color() { 255 } else { 0 },
+            ..LightValues::default()
+        }
+    }
+
+    /// Get a copy with some of the values changed.
+    ///
+    /// 8-bit colors are stretched to 16 bits, so full is full either way.
+    fn with(&self, change: &LightChange) -> LightValues {
+        LightValues {
+            red: change.red.unwrap_or(self.red),
+            green: change.green.unwrap_or(self.green),
+            blue: change.blue.unwrap_or(self.blue),
+            red_fine: change.red_fine.or(change.red).unwrap_or(self.red_fine),
+            green_fine: change.green_fine.or(change.green).unwrap_or(self.green_fine),
+            blue_fine: change.blue_fine.or(change.blue).unwrap_or(self.blue_fine),
+            white: change.white.unwrap_or(self.white),
+            amber: change.amber.unwrap_or(self.amber),
+            dimmer: change.dimmer.unwrap_or(self.dimmer),
+            pan: change.pan.unwrap_or(self.pan),
+            tilt: change.tilt.unwrap_or(self.tilt),
+            color_wheel: change.color_wheel.unwrap_or(self.color_wheel),
+            gobo: change.gobo.unwrap_or(self.gobo),
+            shutter: change.shutter.unwrap_or(self.shutter),
+        }
+    }
+
+    /// The color with 16 bits per channel.
+    fn color16(&self) -> [u16; 3] {
+        [
+            u16::from_be_bytes([self.red, self.red_fine]),
+            u16::from_be_bytes([self.green, self.green_fine]),
+            u16::from_be_bytes([self.blue, self.blue_fine]),
+        ]
+    }
+}
+
+/// New values for some of a light's channels. Missing values are left as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LightChange {
+    pub red: Option<u8>,
+    pub green: Option<u8>,
+    pub blue: Option<u8>,
+    pub red_fine: Option<u8>,
+    pub green_fine: Option<u8>,
+    pub blue_fine: Option<u8>,
+    pub white: Option<u8>,
+    pub amber: Option<u8>,
+    pub dimmer: Option<u8>,
+    pub pan: Option<u16>,
+    pub tilt: Option<u16>,
+    pub color_wheel: Option<u8>,
+    pub gobo: Option<u8>,
+    pub shutter: Option<u8>,
+}
+
+impl LightChange {
+    /// A change of the color channels.
+    pub fn rgb(red: u8, green: u8, blue: u8) -> LightChange {
+        LightChange {
+            red: Some(red),
+            green: Some(green),
+            blue: Some(blue),
+            ..LightChange::default()
+        }
+    }
+
+    /// A change of the color channels with 16 bits per channel.
+    pub fn rgb16(red: u16, green: u16, blue: u16) -> LightChange {
+        let [red, red_fine] = red.to_be_bytes();
+        let [green, green_fine] = green.to_be_bytes();
+        let [blue, blue_fine] = blue.to_be_bytes();
+        LightChange {
+            red_fine: Some(red_fine),
+            green_fine: Some(green_fine),
+            blue_fine: Some(blue_fine),
+            ..LightChange::rgb(red, green, blue)
+        }
+    }
+}
+
+/// Render an effect into a source's layer on some lights.
+fn render_effect(
+    lights: &mut HashMap<u8, Light>,
+    effect: &config::Effect,
+    time: f32,
+    ids: &[u8],
+    source: &SourceKey,
+    priority: u8,
+    mode: MergeMode,
+) {
+    for (index, id) in ids.iter().enumerate() {
+        if let Some(light) = lights.get_mut(id) {
+            let change = effect::render(effect, time, index, ids.len());
+            light.apply(&change, source.clone(), priority, mode);
+        }
+    }
+}
+
+/// Get the lights a command for a logical address goes to: the members
+/// of a group, or just the address itself.
+fn target_ids<'a>(group_lights: &'a HashMap<u8, Vec<u8>>, id: &'a u8) -> &'a [u8] {
+    match group_lights.get(id) {
+        Some(lights) => lights,
+        None => std::slice::from_ref(id),
+    }
+}
+
+/// Convert a color from HSV to 16-bit RGB.
+///
+/// The hue goes around the whole color circle, starting and ending at red.
+fn hsv_to_rgb16(hue: u16, saturation: u8, value: u8) -> [u16; 3] {
+    let hue = f32::from(hue) / 65536.0 * 6.0;
+    let saturation = f32::from(saturation) / 255.0;
+    let value = f32::from(value) / 255.0;
+
+    let sector = hue.floor();
+    let fraction = hue - sector;
+    let p = value * (1.0 - saturation);
+    let q = value * (1.0 - saturation * fraction);
+    let t = value * (1.0 - saturation * (1.0 - fraction));
+    let rgb = match sector as u8 {
+        0 => [value, t, p],
+        1 => [q, value, p],
+        2 => [p, value, t],
+        3 => [p, q, value],
+        4 => [t, p, value],
+        _ => [value, p, q],
+    };
+    rgb.map(|channel| (channel * 65535.0).round() as u16)
+}
+
+/// Snapshot of a light's state for APIs.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LightInfo {
+    /// Logical light id.
+    pub id: u8,
+    /// Human-readable name.
+    pub name: String,
+    /// Type of the light.
+    #[serde(rename = "type")]
+    pub light_type: LightType,
+    /// Name of the host this light is connected to.
+    pub host: String,
+    /// Host-specific address for the light.
+    pub address: usize,
+    /// Current channel values.
+    #[serde(flatten)]
+    pub values: LightValues,
+    /// IP address of the source whose values are on top.
+    pub ip: Option<IpAddr>,
+    /// Nick of the source whose values are on top.
+    pub nick: Option<String>,
+}
+
+/// Snapshot of a running effect for APIs.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectInfo {
+    pub name: String,
+    #[serde(flatten)]
+    pub effect: config::Effect,
+    pub lights: Vec<u8>,
+}
+
+/// Snapshot of the scenes for APIs.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScenesInfo {
+    /// Names of the stored scenes.
+    pub scenes: Vec<String>,
+    /// Name of the recalled scene.
+    pub active: Option<String>,
 }
 
 /// Mappers read commands and issue them to host devices.
@@ -31,7 +518,51 @@ pub struct Mapper {
     /// Configured lights.
     lights: HashMap<u8, Light>,
     /// Configured light effect hosts.
-    light_hosts: Vec<Box<LightHost>>,
+    light_hosts: Vec<Box<dyn LightHost>>,
+    /// Hosts that have received commands since their last flush.
+    dirty_hosts: Vec<bool>,
+    /// When each host was last flushed successfully.
+    flush_times: Vec<Instant>,
+    /// Configured names of the light effect hosts.
+    host_names: Vec<String>,
+    /// Fixture profiles used by the lights.
+    fixtures: Vec<config::Fixture>,
+    /// Lights rendered since the last `take_changed_lights`.
+    changed_lights: Vec<u8>,
+    /// Ordering state of v2 message sources.
+    sources: HashMap<SourceKey, SourceOrder>,
+    /// Grand master and group masters.
+    masters: Masters,
+    /// Lights of the groups that have a logical address.
+    group_lights: HashMap<u8, Vec<u8>>,
+    /// Source priorities.
+    source_rules: SourceRules,
+    /// How sources with the same priority are merged.
+    merge_mode: MergeMode,
+    /// When each source last sent something.
+    source_activity: HashMap<SourceKey, Instant>,
+    /// How long a source can be inactive before its layers are released.
+    release_timeout: Option<Duration>,
+    /// Screensaver settings.
+    idle: Option<config::Idle>,
+    /// When anybody last sent anything.
+    last_activity: Instant,
+    /// When the screensaver started, if it's running.
+    screensaver_start: Option<Instant>,
+    /// Named effects from the configuration.
+    effect_presets: HashMap<String, config::EffectPreset>,
+    /// Effects that are running.
+    effects: Vec<RunningEffect>,
+    /// Stored scenes.
+    scenes: Scenes,
+    /// File the scenes are stored in.
+    scenes_path: PathBuf,
+    /// Name of the scene that was recalled last, if it hasn't been released.
+    recalled: Option<String>,
+    /// Crossfade to the recalled scene, while it's in progress.
+    recall: Option<Recall>,
+    /// Cue list playback.
+    cues: CueList,
     /// Command parser/buffer.
     parser: CommandParser,
 }
@@ -46,6 +577,20 @@ pub enum MapperError {
     UnknownTag(u8),
     /// Unknown logical address.
     UnknownAddr(u8),
+    /// A light refers to a host that isn't configured.
+    UnknownHost(String),
+    /// A light refers to a fixture profile that isn't configured.
+    UnknownFixture(String),
+    /// Unknown effect name.
+    UnknownEffect(String),
+    /// An effect's parameters are out of range.
+    InvalidEffect(String),
+    /// Unknown scene name.
+    UnknownScene(String),
+    /// Unknown light group.
+    UnknownGroup(String),
+    /// A v2 message arrived out of order and was dropped.
+    StaleMessage(u32),
     /// The parser couldn't understand the message.
     ParserError(ParserError),
     /// Some sort of I/O error occurred.
@@ -63,7 +608,8 @@ impl Mapper {
     /// Try to set up a mapper and its host devices from a configuration.
     pub fn from_config(config: &Root) -> MapperResult<Mapper> {
         let mut lights: HashMap<u8, Light> = HashMap::new();
-        let mut light_hosts: Vec<Box<LightHost>> = vec![];
+        let mut light_hosts: Vec<Box<dyn LightHost>> = vec![];
+        let mut host_names: Vec<String> = vec![];
 
         // Helper for assigning lights to hosts.
         let mut light_hosts_lookup: HashMap<String, usize> = HashMap::new();
@@ -71,51 +617,140 @@ impl Mapper {
         // Read host device information.
         for (id, host) in &config.hosts {
             light_hosts_lookup.insert(id.clone(), light_hosts.len());
-            let host_device: Box<LightHost> = match host {
+            let host_device: Box<dyn LightHost> = match host {
                 config::Host::Enttec { path } => Box::new(
                     host::Enttec::new(path.as_ref()).expect("Unable to initialize Enttec device!"),
                 ),
                 config::Host::Proxy { addr } => Box::new(
                     host::UdpProxy::new(addr).expect("Unable to initialize Proxy device!")
                 ),
+                config::Host::ArtNet { addr, net, subnet, universe } => Box::new(
+                    host::ArtNet::new(addr, *net, *subnet, *universe)
+                        .expect("Unable to initialize Art-Net device!")
+                ),
+                config::Host::Sacn { universe, addr, priority, source_name, cid } => Box::new(
+                    host::Sacn::new(
+                        *universe,
+                        addr.as_ref(),
+                        priority.unwrap_or(host::sacn::DEFAULT_PRIORITY),
+                        source_name.as_ref().map_or("effectserver2-rs", String::as_str),
+                        cid.as_ref()
+                            .and_then(|cid| host::sacn::parse_cid(cid))
+                            .unwrap_or_else(host::sacn::random_cid),
+                    )
+                    .expect("Unable to initialize sACN device!")
+                ),
             };
             light_hosts.push(host_device);
+            host_names.push(id.clone());
         }
 
+        // Set up fixture profiles. Default layouts get added as they're needed.
+        let mut fixtures: Vec<config::Fixture> = vec![];
+        let mut fixtures_lookup: HashMap<String, usize> = HashMap::new();
+        for (name, fixture) in &config.fixtures {
+            fixtures_lookup.insert(name.clone(), fixtures.len());
+            fixtures.push(fixture.clone());
+        }
+        let mut default_fixtures_lookup: HashMap<LightType, usize> = HashMap::new();
+
         // Set up lights and their host device mapping.
         for (id, light) in &config.mapping.lights {
-            match light {
-                config::Light::Rgb {
-                    host,
-                    address,
-                    name,
-                } => {
-                    let host_index = 0;
-
-                    lights.insert(
-                        *id,
-                        Light {
-                            name: name.clone().unwrap_or_else(|| format!("{}-{}", host, id)),
-                            host_index,
-                            address: *address as usize,
-                            red: 0,
-                            green: 0,
-                            blue: 0,
-                            ip: None,
-                        },
-                    );
-                }
-            }
+            let light_type = light.light_type();
+            let config::LightSettings {
+                host,
+                address,
+                name,
+                fixture,
+                calibration,
+            } = light.settings();
+
+            let host_index = *light_hosts_lookup
+                .get(host)
+                .ok_or_else(|| MapperError::UnknownHost(host.clone()))?;
+            let fixture_index = match fixture {
+                Some(fixture) => *fixtures_lookup
+                    .get(fixture)
+                    .ok_or_else(|| MapperError::UnknownFixture(fixture.clone()))?,
+                None => *default_fixtures_lookup.entry(light_type).or_insert_with(|| {
+                    fixtures.push(config::Fixture::default_for(light_type));
+                    fixtures.len() - 1
+                }),
+            };
+
+            lights.insert(
+                *id,
+                Light {
+                    name: name.clone().unwrap_or_else(|| format!("{}-{}", host, id)),
+                    light_type,
+                    host_index,
+                    address: *address as usize,
+                    fixture_index,
+                    calibration: calibration
+                        .clone()
+                        .or_else(|| fixtures[fixture_index].calibration.clone())
+                        .unwrap_or_default(),
+                    // Lights with a dimmer channel are dimmed with it.
+                    master_dimmer: !light_type.has_color()
+                        || fixtures[fixture_index].channels.contains(&FixtureChannel::Dimmer),
+                    values: LightValues::initial(light_type),
+                    layers: vec![],
+                    ip: None,
+                    nick: None,
+                    dirty: false,
+                },
+            );
         }
 
+        let scenes_path = &config.server.scenes_file;
+        // Bad scenes shouldn't keep the lights from working.
+        let scenes = scene::load(scenes_path.as_ref()).unwrap_or_else(|err| {
+            eprintln!(
+                "Unable to read scenes from {}, starting without them: {:?}",
+                scenes_path, err
+            );
+            Scenes::new()
+        });
+
         Ok(Mapper {
             lights,
+            dirty_hosts: vec![false; light_hosts.len()],
+            flush_times: vec![Instant::now(); light_hosts.len()],
             light_hosts,
+            host_names,
+            fixtures,
+            changed_lights: vec![],
+            sources: HashMap::new(),
+            masters: Masters::from_config(&config.mapping.groups),
+            group_lights: config
+                .mapping
+                .groups
+                .values()
+                .filter_map(|group| Some((group.id?, group.lights.clone())))
+                .collect(),
+            source_rules: SourceRules::from_config(&config.merge),
+            merge_mode: config.merge.mode,
+            source_activity: HashMap::new(),
+            release_timeout: config.merge.release_timeout.map(Duration::from_secs_f32),
+            idle: config.idle.clone(),
+            last_activity: Instant::now(),
+            screensaver_start: None,
+            effect_presets: config.effects.clone(),
+            effects: vec![],
+            scenes,
+            scenes_path: scenes_path.into(),
+            recalled: None,
+            recall: None,
+            cues: CueList::new(
+                cue::load(config.server.cues_file.as_ref()).map_err(MapperError::IoError)?,
+            ),
             parser: CommandParser::new(),
         })
     }
 
-    /// Read a message from a buffer and issue some commands.
+    /// Read a message from a buffer and update the light state.
+    ///
+    /// Nothing is sent to the hosts until the next `render_frame`.
     ///
     /// TODO: Should the messages be parsed by the servers themselves?
     /// Or would that move too much "business logic" into them?
@@ -123,62 +758,1123 @@ impl Mapper {
         let mut reader = std::io::BufReader::new(buf);
         self.parser.read_from(&mut reader)?;
 
+        let header = self.parser.header;
+        if let (Some(sequence), Some(timestamp)) = (header.sequence, header.timestamp) {
+            let nick = self.parser.cmds.iter().find_map(|cmd| match cmd {
+                Command::Nick { nick } => Some(nick.clone()),
+                _ => None,
+            });
+            self.check_order((ip, nick), sequence, timestamp)?;
+        }
+        self.wake();
+
         let mut last_nick: Option<String> = None;
+        // Effects and scenes are handled after going through the commands.
+        let mut effect_cmds = vec![];
+        let mut scene_cmds = vec![];
+        let mut cue_cmds = vec![];
 
         for cmd in &self.parser.cmds {
-            match cmd {
+            let (id, change) = match cmd {
                 Command::Nick { nick } => {
                     // ewww, clone
-                    last_nick = Some(nick.clone())
+                    last_nick = Some(nick.clone());
+                    continue;
                 }
-                Command::RgbLight {
+                Command::RgbLight { id, red, green, blue, .. } => {
+                    (id, LightChange::rgb(*red, *green, *blue))
+                }
+                Command::Rgb16Light { id, red, green, blue } => {
+                    (id, LightChange::rgb16(*red, *green, *blue))
+                }
+                Command::HsvLight { id, hue, saturation, value } => {
+                    let [red, green, blue] = hsv_to_rgb16(*hue, *saturation, *value);
+                    (id, LightChange::rgb16(red, green, blue))
+                }
+                Command::RgbwLight { id, red, green, blue, white } => (
                     id,
-                    light_type,
-                    red,
-                    green,
-                    blue,
-                } => {
-                    // Look for a light with a given id
-                    let light = self
-                        .lights
-                        .get_mut(&id);
-                    if light.is_none() {
-                        eprintln!("Unknown light id {}", id);
-                        continue;
+                    LightChange {
+                        white: Some(*white),
+                        ..LightChange::rgb(*red, *green, *blue)
+                    },
+                ),
+                Command::RgbaLight { id, red, green, blue, amber } => (
+                    id,
+                    LightChange {
+                        amber: Some(*amber),
+                        ..LightChange::rgb(*red, *green, *blue)
+                    },
+                ),
+                Command::DimmerLight { id, level } => (
+                    id,
+                    LightChange {
+                        dimmer: Some(*level),
+                        ..LightChange::default()
+                    },
+                ),
+                Command::RelayLight { id, on } => (
+                    id,
+                    LightChange {
+                        dimmer: Some(if *on { 255 } else { 0 }),
+                        ..LightChange::default()
+                    },
+                ),
+                Command::Position { id, pan, tilt, color_wheel, gobo, shutter } => (
+                    id,
+                    LightChange {
+                        pan: Some(*pan),
+                        tilt: Some(*tilt),
+                        color_wheel: Some(*color_wheel),
+                        gobo: Some(*gobo),
+                        shutter: Some(*shutter),
+                        ..LightChange::default()
+                    },
+                ),
+                Command::Effect { name, start } => {
+                    effect_cmds.push((name.clone(), *start, last_nick.clone()));
+                    continue;
+                }
+                Command::Scene { action, name, duration } => {
+                    scene_cmds.push((*action, name.clone(), *duration));
+                    continue;
+                }
+                Command::Cue { action } => {
+                    cue_cmds.push(*action);
+                    continue;
+                }
+                Command::Master { group, level } => {
+                    // The empty name is the grand master.
+                    let group = Some(group.as_str()).filter(|group| !group.is_empty());
+                    if let Err(err) = self.masters.set(group, *level, &mut self.lights) {
+                        eprintln!("Unknown master: {:?}", err);
                     }
-                    let light = light.unwrap();
-                        // .ok_or_else(|| MapperError::UnknownAddr(*id))?;
-                    // Check that its type matches the command
-                    // TODO: Actually do that.
-                    if *light_type != 0 {
-                        eprintln!("Unknown light type {}", light_type);
+                    continue;
+                }
+                Command::Fade { id, red, green, blue, duration, easing } => {
+                    let to = [*red, *green, *blue].map(|value| u16::from(value) * 257);
+                    let duration = Duration::from_millis(u64::from(*duration));
+                    let priority = self.source_rules.priority(ip, last_nick.as_deref());
+                    self.source_activity.insert((ip, last_nick.clone()), Instant::now());
+                    for id in target_ids(&self.group_lights, id) {
+                        match self.lights.get_mut(id) {
+                            Some(light) => light.start_fade(
+                                to,
+                                duration,
+                                Easing::from_id(*easing),
+                                (ip, last_nick.clone()),
+                                priority,
+                            ),
+                            None => eprintln!("Unknown light id {}", id),
+                        }
                     }
+                    continue;
+                }
+            };
 
-                    light.red = *red;
-                    light.green = *green;
-                    light.blue = *blue;
-                    light.ip = ip;
+            // Look for a light or a group with a given id
+            let priority = self.source_rules.priority(ip, last_nick.as_deref());
+            self.source_activity.insert((ip, last_nick.clone()), Instant::now());
+            for id in target_ids(&self.group_lights, id) {
+                match self.lights.get_mut(id) {
+                    Some(light) => light.apply(
+                        &change,
+                        (ip, last_nick.clone()),
+                        priority,
+                        self.merge_mode,
+                    ),
+                    None => eprintln!("Unknown light id {}", id),
+                }
+            }
+        }
+
+        for (name, start, nick) in effect_cmds {
+            let result = if start {
+                self.start_effect(&name, None, None, ip, nick)
+            } else if name.is_empty() {
+                self.stop_effects();
+                Ok(())
+            } else {
+                self.stop_effect(&name)
+            };
+            if let Err(err) = result {
+                eprintln!("Effect fail: {:?}", err);
+            }
+        }
 
-                    // Issue a command to its host
-                    let host = &mut self.light_hosts[light.host_index];
-                    host.take_command(&LightCommand {
-                        id: *id as usize,
-                        address: light.address,
-                        red: *red,
-                        green: *green,
-                        blue: *blue,
-                    })
-                    // And record that the host needs a flush
-                    // TODO: Actually do that.
+        for (action, name, duration) in scene_cmds {
+            let result = match action {
+                scene_action::STORE => self.store_scene(&name),
+                scene_action::RELEASE => {
+                    self.release_scene();
+                    Ok(())
                 }
+                _ => self.recall_scene(&name, Duration::from_millis(u64::from(duration))),
+            };
+            if let Err(err) = result {
+                eprintln!("Scene fail: {:?}", err);
             }
         }
 
-        // TODO: Only flush the hosts that were used.
-        for host in &mut self.light_hosts {
-            host.flush();
+        for action in cue_cmds {
+            match action {
+                cue_action::GO => self.go_cue(),
+                cue_action::BACK => self.back_cue(),
+                cue_action::PAUSE => self.pause_cues(),
+                _ => self.resume_cues(),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Store the current state of every light as a scene, and save the scenes.
+    pub fn store_scene(&mut self, name: &str) -> MapperResult<()> {
+        let scene = self.lights.iter().map(|(id, light)| (*id, light.values)).collect();
+        self.scenes.insert(name.to_owned(), scene);
+        self.save_scenes()
+    }
+
+    /// Delete a scene, and save the scenes. The scene is released if it's
+    /// the recalled one.
+    pub fn delete_scene(&mut self, name: &str) -> MapperResult<()> {
+        self.scenes
+            .remove(name)
+            .ok_or_else(|| MapperError::UnknownScene(name.to_owned()))?;
+        if self.recalled.as_deref() == Some(name) {
+            self.release_scene();
         }
+        self.save_scenes()
+    }
 
+    fn save_scenes(&self) -> MapperResult<()> {
+        scene::save(&self.scenes_path, &self.scenes).map_err(MapperError::IoError)
+    }
+
+    /// Start crossfading to a scene.
+    ///
+    /// The scene stays on its own layer until it's released or another
+    /// scene is recalled.
+    pub fn recall_scene(&mut self, name: &str, duration: Duration) -> MapperResult<()> {
+        if !self.scenes.contains_key(name) {
+            return Err(MapperError::UnknownScene(name.to_owned()));
+        }
+        self.wake();
+        self.recalled = Some(name.to_owned());
+        self.recall = Some(Recall {
+            name: name.to_owned(),
+            from: self.lights.iter().map(|(id, light)| (*id, light.values)).collect(),
+            start: Instant::now(),
+            duration,
+        });
         Ok(())
     }
+
+    /// Let go of the recalled scene, revealing whatever is underneath.
+    pub fn release_scene(&mut self) {
+        self.recall = None;
+        if self.recalled.take().is_some() {
+            let source = scene_source();
+            for light in self.lights.values_mut() {
+                light.release(&source, self.merge_mode);
+            }
+        }
+    }
+
+    /// Get the stored scenes and the recalled one.
+    pub fn scenes_info(&self) -> ScenesInfo {
+        ScenesInfo {
+            scenes: self.scenes.keys().cloned().collect(),
+            active: self.recalled.clone(),
+        }
+    }
+
+    /// Go to the next cue in the cue list.
+    pub fn go_cue(&mut self) {
+        if let Some(cue) = self.cues.go(Instant::now()).cloned() {
+            self.play_cue(&cue);
+        }
+    }
+
+    /// Go back to the previous cue in the cue list.
+    pub fn back_cue(&mut self) {
+        if let Some(cue) = self.cues.back(Instant::now()).cloned() {
+            self.play_cue(&cue);
+        }
+    }
+
+    /// Hold the wait before the next cue.
+    pub fn pause_cues(&mut self) {
+        self.cues.pause(Instant::now());
+    }
+
+    /// Continue a held wait before the next cue.
+    pub fn resume_cues(&mut self) {
+        self.cues.resume(Instant::now());
+    }
+
+    /// Get the cue list and where playback is at.
+    pub fn cues_info(&self) -> CuesInfo {
+        self.cues.info(Instant::now())
+    }
+
+    /// Recall a cue's scene and start its effects.
+    ///
+    /// Missing scenes and effects are skipped, so a mistake in the cue list
+    /// doesn't stop the show.
+    fn play_cue(&mut self, cue: &Cue) {
+        if let Some(scene) = &cue.scene {
+            if let Err(err) = self.recall_scene(scene, cue.fade_duration()) {
+                eprintln!("Cue fail: {:?}", err);
+            }
+        }
+        if let Some(effects) = &cue.effects {
+            // Effects started some other way keep running.
+            let names: Vec<String> = self
+                .effects
+                .iter()
+                .filter(|running| running.started_by == cue_source())
+                .map(|running| running.name.clone())
+                .collect();
+            for name in names {
+                let _ = self.stop_effect(&name);
+            }
+            for name in effects {
+                let (ip, nick) = cue_source();
+                if let Err(err) = self.start_effect(name, None, None, ip, nick) {
+                    eprintln!("Cue fail: {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Move the recalled scene's crossfade along.
+    ///
+    /// Once the crossfade is done, the scene's values stay on its layer
+    /// without being applied again, so other sources can take lights over.
+    fn run_scene(&mut self, now: Instant) {
+        let recall = match &self.recall {
+            Some(recall) => recall,
+            None => return,
+        };
+        let scene = match self.scenes.get(&recall.name) {
+            Some(scene) => scene,
+            None => return,
+        };
+        let t = recall.progress(now);
+        let priority = self.source_rules.priority(None, Some("scene"));
+        for (id, to) in scene {
+            if let (Some(light), Some(from)) = (self.lights.get_mut(id), recall.from.get(id)) {
+                let change = scene::mix(from, to, t);
+                light.apply(&change, scene_source(), priority, self.merge_mode);
+            }
+        }
+        if t >= 1.0 {
+            self.recall = None;
+        }
+    }
+
+    /// Start an effect, replacing any running effect with the same name.
+    ///
+    /// Without parameters, the effect is looked up from the named effects
+    /// and then from the built-in effects with their default parameters.
+    pub fn start_effect(
+        &mut self,
+        name: &str,
+        effect: Option<config::Effect>,
+        lights: Option<Vec<u8>>,
+        ip: Option<IpAddr>,
+        nick: Option<String>,
+    ) -> MapperResult<()> {
+        let preset = self.effect_presets.get(name);
+        let effect = match effect.or_else(|| preset.map(|preset| preset.effect.clone())) {
+            Some(effect) => effect,
+            None => config::Effect::builtin(name)
+                .ok_or_else(|| MapperError::UnknownEffect(name.to_owned()))?,
+        };
+        if !effect.is_valid() {
+            return Err(MapperError::InvalidEffect(name.to_owned()));
+        }
+        let lights = match lights.or_else(|| preset.and_then(|preset| preset.lights.clone())) {
+            Some(ids) => {
+                let mut lights = vec![];
+                for id in &ids {
+                    for id in target_ids(&self.group_lights, id) {
+                        if !self.lights.contains_key(id) {
+                            return Err(MapperError::UnknownAddr(*id));
+                        }
+                        lights.push(*id);
+                    }
+                }
+                lights
+            }
+            None => {
+                let mut lights: Vec<u8> = self
+                    .lights
+                    .iter()
+                    .filter(|(_, light)| light.light_type != LightType::Relay)
+                    .map(|(id, _)| *id)
+                    .collect();
+                lights.sort();
+                lights
+            }
+        };
+
+        if self.effects.iter().any(|running| running.name == name) {
+            self.stop_effect(name)?;
+        }
+        self.wake();
+        self.effects.push(RunningEffect {
+            name: name.to_owned(),
+            effect,
+            lights,
+            start: Instant::now(),
+            source: (None, Some(format!("effect:{}", name))),
+            priority: self.source_rules.priority(ip, nick.as_deref()),
+            started_by: (ip, nick),
+        });
+        Ok(())
+    }
+
+    /// Stop a running effect, revealing whatever is underneath.
+    pub fn stop_effect(&mut self, name: &str) -> MapperResult<()> {
+        let index = self
+            .effects
+            .iter()
+            .position(|running| running.name == name)
+            .ok_or_else(|| MapperError::UnknownEffect(name.to_owned()))?;
+        let running = self.effects.remove(index);
+        for id in &running.lights {
+            if let Some(light) = self.lights.get_mut(id) {
+                light.release(&running.source, self.merge_mode);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop every running effect.
+    pub fn stop_effects(&mut self) {
+        let names: Vec<String> = self.effects.iter().map(|running| running.name.clone()).collect();
+        for name in names {
+            let _ = self.stop_effect(&name);
+        }
+    }
+
+    /// Get a snapshot of the running effects.
+    pub fn effects_info(&self) -> Vec<EffectInfo> {
+        self.effects
+            .iter()
+            .map(|running| EffectInfo {
+                name: running.name.clone(),
+                effect: running.effect.clone(),
+                lights: running.lights.clone(),
+            })
+            .collect()
+    }
+
+    /// Check that a v2 message is newer than the last one from its source.
+    ///
+    /// Sequence numbers are compared with wrap-around, and timestamps must
+    /// not go backwards either.
+    fn check_order(&mut self, key: SourceKey, sequence: u32, timestamp: u64) -> MapperResult<()> {
+        let now = Instant::now();
+        if let Some(last) = self.sources.get(&key) {
+            let recent = now.duration_since(last.seen) < SOURCE_RESET_TIME;
+            let newer = (sequence.wrapping_sub(last.sequence) as i32) > 0;
+            if recent && (!newer || timestamp < last.timestamp) {
+                return Err(MapperError::StaleMessage(sequence));
+            }
+        }
+        self.sources.insert(key, SourceOrder { sequence, timestamp, seen: now });
+
+        // Forget sources that have gone quiet every now and then.
+        if self.sources.len() > 64 {
+            self.sources
+                .retain(|_, source| now.duration_since(source.seen) < SOURCE_RESET_TIME);
+        }
+        Ok(())
+    }
+
+    /// Set the lights of a host from raw DMX channel data, as if the lights
+    /// were listening to the channels themselves.
+    ///
+    /// `channels` starts from DMX channel 1. Lights are only touched if
+    /// their values actually change.
+    pub fn take_dmx(
+        &mut self,
+        host: &str,
+        channels: &[u8],
+        ip: Option<IpAddr>,
+        nick: &str,
+    ) -> MapperResult<()> {
+        let host_index = self
+            .host_names
+            .iter()
+            .position(|name| name == host)
+            .ok_or_else(|| MapperError::UnknownHost(host.to_owned()))?;
+        self.wake();
+        let priority = self.source_rules.priority(ip, Some(nick));
+        self.source_activity.insert((ip, Some(nick.to_owned())), Instant::now());
+
+        for light in self.lights.values_mut() {
+            if light.host_index != host_index || light.address == 0 {
+                continue;
+            }
+            // Read the channels back the way the fixture profile lays them out.
+            let mut change = LightChange::default();
+            let (mut pan, mut tilt) = ([None, None], [None, None]);
+            let mut fine = [None; 3];
+            let layout = &self.fixtures[light.fixture_index].channels;
+            for (offset, channel) in layout.iter().enumerate() {
+                let value = match channels.get(light.address - 1 + offset) {
+                    Some(value) => *value,
+                    None => break,
+                };
+                match channel {
+                    FixtureChannel::Red => change.red = Some(value),
+                    FixtureChannel::Green => change.green = Some(value),
+                    FixtureChannel::Blue => change.blue = Some(value),
+                    FixtureChannel::RedFine => fine[0] = Some(value),
+                    FixtureChannel::GreenFine => fine[1] = Some(value),
+                    FixtureChannel::BlueFine => fine[2] = Some(value),
+                    FixtureChannel::White => change.white = Some(value),
+                    FixtureChannel::Amber => change.amber = Some(value),
+                    FixtureChannel::Dimmer => change.dimmer = Some(value),
+                    FixtureChannel::Pan => pan[0] = Some(value),
+                    FixtureChannel::PanFine => pan[1] = Some(value),
+                    FixtureChannel::Tilt => tilt[0] = Some(value),
+                    FixtureChannel::TiltFine => tilt[1] = Some(value),
+                    FixtureChannel::ColorWheel => change.color_wheel = Some(value),
+                    FixtureChannel::Gobo => change.gobo = Some(value),
+                    FixtureChannel::Shutter => change.shutter = Some(value),
+                    _ => {}
+                }
+            }
+            // Positions can be split to coarse and fine channels.
+            let position = |[coarse, fine]: [Option<u8>; 2]| {
+                coarse.map(|coarse| u16::from_be_bytes([coarse, fine.unwrap_or(0)]))
+            };
+            change.pan = position(pan);
+            change.tilt = position(tilt);
+            // Without fine channels, 8-bit colors are stretched as usual.
+            change.red_fine = fine[0];
+            change.green_fine = fine[1];
+            change.blue_fine = fine[2];
+            // Desks send the same values over and over, which shouldn't take
+            // the light back from the sources that changed it since.
+            let source = (ip, Some(nick.to_owned()));
+            if light.has_change(&change, &source) {
+                continue;
+            }
+            light.apply(&change, source, priority, self.merge_mode);
+        }
+        Ok(())
+    }
+
+    /// Change a single light's state.
+    pub fn set_light(
+        &mut self,
+        id: u8,
+        change: &LightChange,
+        ip: Option<IpAddr>,
+        nick: Option<String>,
+    ) -> MapperResult<()> {
+        self.wake();
+        let priority = self.source_rules.priority(ip, nick.as_deref());
+        self.source_activity.insert((ip, nick.clone()), Instant::now());
+        let light = self
+            .lights
+            .get_mut(&id)
+            .ok_or(MapperError::UnknownAddr(id))?;
+        light.apply(change, (ip, nick), priority, self.merge_mode);
+        Ok(())
+    }
+
+    /// Get a snapshot of a single light's state.
+    pub fn light_info(&self, id: u8) -> MapperResult<LightInfo> {
+        let light = self.lights.get(&id).ok_or(MapperError::UnknownAddr(id))?;
+        Ok(LightInfo {
+            id,
+            name: light.name.clone(),
+            light_type: light.light_type,
+            host: self.host_names[light.host_index].clone(),
+            address: light.address,
+            values: light.values,
+            ip: light.ip,
+            nick: light.nick.clone(),
+        })
+    }
+
+    /// Set a group's master level, or the grand master's without a group.
+    pub fn set_master(&mut self, group: Option<&str>, level: u8) -> MapperResult<()> {
+        self.masters.set(group, level, &mut self.lights)
+    }
+
+    /// Does a light group exist?
+    pub fn has_group(&self, name: &str) -> bool {
+        self.masters.has_group(name)
+    }
+
+    /// Get a snapshot of the master levels.
+    pub fn masters_info(&self) -> MastersInfo {
+        self.masters.info()
+    }
+
+    /// Get a snapshot of every light's state, ordered by id.
+    pub fn lights_info(&self) -> Vec<LightInfo> {
+        let mut ids: Vec<u8> = self.lights.keys().cloned().collect();
+        ids.sort();
+        ids.into_iter()
+            .filter_map(|id| self.light_info(id).ok())
+            .collect()
+    }
+
+    /// Issue commands for lights that changed since the last frame and flush their hosts.
+    ///
+    /// Any number of messages taken between two frames get coalesced into one
+    /// command per light. Inactive sources are released, cues, scenes and
+    /// effects are run and fades in progress are moved along first.
+    pub fn render_frame(&mut self) -> MapperResult<()> {
+        let now = Instant::now();
+        self.release_inactive_sources(now);
+        self.run_screensaver(now);
+        if let Some(cue) = self.cues.follow(now).cloned() {
+            self.play_cue(&cue);
+        }
+        self.run_scene(now);
+        for running in &self.effects {
+            let time = now.duration_since(running.start).as_secs_f32();
+            render_effect(
+                &mut self.lights,
+                &running.effect,
+                time,
+                &running.lights,
+                &running.source,
+                running.priority,
+                self.merge_mode,
+            );
+        }
+        for light in self.lights.values_mut() {
+            light.step_fades(now, self.merge_mode);
+        }
+
+        for (id, light) in &mut self.lights {
+            if !light.dirty {
+                continue;
+            }
+            // Issue a command to its host
+            let host = &mut self.light_hosts[light.host_index];
+            let values = light.output_values(self.masters.level(*id));
+            host.take_command(&LightCommand {
+                id: *id as usize,
+                address: light.address,
+                channels: &self.fixtures[light.fixture_index].channels,
+                red: values.red,
+                green: values.green,
+                blue: values.blue,
+                red_fine: values.red_fine,
+                green_fine: values.green_fine,
+                blue_fine: values.blue_fine,
+                white: values.white,
+                amber: values.amber,
+                dimmer: values.dimmer,
+                pan: values.pan,
+                tilt: values.tilt,
+                color_wheel: values.color_wheel,
+                gobo: values.gobo,
+                shutter: values.shutter,
+            });
+            // And record that the host needs a flush
+            self.dirty_hosts[light.host_index] = true;
+            light.dirty = false;
+            if !self.changed_lights.contains(id) {
+                self.changed_lights.push(*id);
+            }
+        }
+
+        // Some hosts need to be refreshed even when nothing changes.
+        for (index, host) in self.light_hosts.iter().enumerate() {
+            if let Some(interval) = host.refresh_interval() {
+                if now.duration_since(self.flush_times[index]) >= interval {
+                    self.dirty_hosts[index] = true;
+                }
+            }
+        }
+
+        self.flush_hosts()
+    }
+
+    /// Run the screensaver if nobody has sent anything in a while and
+    /// there are no effects running.
+    ///
+    /// Relays are left alone, since there's no telling what they're connected to.
+    fn run_screensaver(&mut self, now: Instant) {
+        let idle = match &self.idle {
+            Some(idle) => idle,
+            None => return,
+        };
+        let after = Duration::from_secs_f32(idle.after);
+        if now.duration_since(self.last_activity) < after || !self.effects.is_empty() {
+            return;
+        }
+        let start = *self.screensaver_start.get_or_insert(now);
+        let time = now.duration_since(start).as_secs_f32();
+        let mut ids: Vec<u8> = self
+            .lights
+            .iter()
+            .filter(|(_, light)| light.light_type != LightType::Relay)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        let source = screensaver_source();
+        let mode = self.merge_mode;
+        render_effect(&mut self.lights, &idle.effect, time, &ids, &source, u8::MAX, mode);
+    }
+
+    /// Note that somebody sent something, stopping the screensaver.
+    fn wake(&mut self) {
+        self.last_activity = Instant::now();
+        if self.screensaver_start.take().is_some() {
+            let source = screensaver_source();
+            for light in self.lights.values_mut() {
+                light.release(&source, self.merge_mode);
+            }
+        }
+    }
+
+    /// Drop the layers of sources that haven't sent anything in a while.
+    fn release_inactive_sources(&mut self, now: Instant) {
+        let timeout = match self.release_timeout {
+            Some(timeout) => timeout,
+            None => {
+                // Nothing gets released, so there's nothing to remember either.
+                self.source_activity.clear();
+                return;
+            }
+        };
+        let inactive: Vec<SourceKey> = self
+            .source_activity
+            .iter()
+            .filter(|(_, seen)| now.duration_since(**seen) >= timeout)
+            .map(|(source, _)| source.clone())
+            .collect();
+        for source in inactive {
+            self.source_activity.remove(&source);
+            for light in self.lights.values_mut() {
+                light.release(&source, self.merge_mode);
+            }
+        }
+    }
+
+    /// Get the state of every light rendered since the last call.
+    pub fn take_changed_lights(&mut self) -> Vec<LightInfo> {
+        let mut ids = std::mem::take(&mut self.changed_lights);
+        ids.sort();
+        ids.into_iter()
+            .filter_map(|id| self.light_info(id).ok())
+            .collect()
+    }
+
+    /// Flush the hosts that have received commands since their last flush.
+    ///
+    /// Every dirty host gets a flush attempt even if an earlier one fails.
+    /// Failed hosts stay dirty, and the first error is returned.
+    fn flush_hosts(&mut self) -> MapperResult<()> {
+        let mut result = Ok(());
+        let hosts = self.light_hosts.iter_mut().zip(self.dirty_hosts.iter_mut());
+        for ((host, dirty), flush_time) in hosts.zip(self.flush_times.iter_mut()) {
+            if !*dirty {
+                continue;
+            }
+            match host.flush() {
+                Ok(()) => {
+                    *dirty = false;
+                    *flush_time = Instant::now();
+                }
+                Err(err) => {
+                    if result.is_ok() {
+                        result = Err(MapperError::IoError(err));
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration with two proxy hosts and some configuration of the
+    /// `mapping` section and the sections after it.
+    fn config(rest: &str) -> Root {
+        let yaml = format!(
+            r#"
+server:
+  udpAddr: "127.0.0.1:0"
+  webAddr: "127.0.0.1:0"
+  websocketAddr: "127.0.0.1:0"
+  scenesFile: "does-not-exist/scenes.yaml"
+  cuesFile: "does-not-exist/cues.yaml"
+hosts:
+  first:
+    type: "proxy"
+    addr: "127.0.0.1:9"
+  second:
+    type: "proxy"
+    addr: "127.0.0.1:9"
+{}"#,
+            rest
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    /// Set up a Mapper with the configuration from `config`.
+    fn mapper(rest: &str) -> MapperResult<Mapper> {
+        Mapper::from_config(&config(rest))
+    }
+
+    #[test]
+    fn lights_go_to_their_own_hosts() {
+        let mapper = mapper(
+            r#"
+mapping:
+  lights:
+    0: {type: "rgb", host: "first", address: 1}
+    1: {type: "rgb", host: "second", address: 1}
+    2: {type: "rgb", host: "second", address: 4}
+"#,
+        )
+        .unwrap();
+        let host = |id: u8| mapper.lights[&id].host_index;
+        assert_ne!(host(0), host(1));
+        assert_eq!(host(1), host(2));
+    }
+
+    #[test]
+    fn malformed_scenes_file_starts_without_scenes() {
+        let path = std::env::temp_dir().join(format!("scenes-{}.yaml", std::process::id()));
+        std::fs::write(&path, "red: [").unwrap();
+        let mut config = config("mapping:\n  lights: {}\n");
+        config.server.scenes_file = path.to_string_lossy().into_owned();
+        let mapper = Mapper::from_config(&config);
+        std::fs::remove_file(&path).unwrap();
+        assert!(mapper.unwrap().scenes.is_empty());
+    }
+
+    #[test]
+    fn dimmer_scales_colors_without_a_dimmer_channel() {
+        let mut mapper = mapper(
+            r#"
+fixtures:
+  withDimmer:
+    channels: [dimmer, red, green, blue]
+mapping:
+  lights:
+    0: {type: "rgb", host: "first", address: 1}
+    1: {type: "rgb", host: "first", address: 4, fixture: "withDimmer"}
+"#,
+        )
+        .unwrap();
+        let msg = [1, 1, 0, 0, 200, 100, 50, 1, 0, 3, 128, 1, 1, 0, 200, 100, 50, 1, 1, 3, 128];
+        mapper.take_msg(&msg, None).unwrap();
+
+        let values = mapper.lights[&0].output_values(1.0);
+        assert_eq!([values.red, values.green, values.blue], [100, 50, 25]);
+        // The dimmer channel does the dimming when there is one.
+        let values = mapper.lights[&1].output_values(1.0);
+        assert_eq!([values.red, values.green, values.blue, values.dimmer], [200, 100, 50, 128]);
+    }
+
+    #[test]
+    fn ltp_resend_takes_the_light_back() {
+        let mut mapper = single_light("mode: ltp");
+        send_red(&mut mapper, "10.0.0.1", 10);
+        send_red(&mut mapper, "10.0.0.2", 200);
+        assert_eq!(mapper.lights[&0].values.red, 200);
+        send_red(&mut mapper, "10.0.0.1", 10);
+        assert_eq!(mapper.lights[&0].values.red, 10);
+        assert_eq!(mapper.lights[&0].ip, Some("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn layers_are_limited() {
+        let mut mapper = single_light("{}");
+        for i in 0..MAX_LAYERS + 8 {
+            let mut msg = vec![1, 0];
+            msg.extend_from_slice(format!("nick{}", i).as_bytes());
+            msg.extend_from_slice(&[0, 1, 0, 0, i as u8, 0, 0]);
+            mapper.take_msg(&msg, None).unwrap();
+        }
+        let light = &mapper.lights[&0];
+        assert_eq!(light.layers.len(), MAX_LAYERS);
+        assert_eq!(usize::from(light.values.red), MAX_LAYERS + 7);
+    }
+
+    #[test]
+    fn unknown_host_is_an_error() {
+        let result = mapper(
+            r#"
+mapping:
+  lights:
+    0: {type: "rgb", host: "third", address: 1}
+"#,
+        );
+        assert!(matches!(result, Err(MapperError::UnknownHost(host)) if host == "third"));
+    }
+
+    /// A light's color, as the API shows it.
+    fn rgb(mapper: &Mapper, id: u8) -> (u8, u8, u8) {
+        let info = mapper.light_info(id).unwrap();
+        (info.values.red, info.values.green, info.values.blue)
+    }
+
+    /// The nick of the source that set a light.
+    fn nick(mapper: &Mapper, id: u8) -> Option<String> {
+        mapper.light_info(id).unwrap().nick
+    }
+
+    #[test]
+    fn dmx_sets_the_lights_of_its_host() {
+        let mut mapper = mapper(
+            r#"
+mapping:
+  lights:
+    0: {type: "rgb", host: "first", address: 1}
+    1: {type: "rgb", host: "first", address: 4}
+    2: {type: "rgb", host: "second", address: 1}
+    3: {type: "rgb", host: "first", address: 7}
+"#,
+        )
+        .unwrap();
+        let desk = Some("10.0.0.9".parse().unwrap());
+        mapper
+            .take_dmx("first", &[1, 2, 3, 4, 5, 6, 7], desk, "artnet")
+            .unwrap();
+        assert_eq!(rgb(&mapper, 0), (1, 2, 3));
+        assert_eq!(rgb(&mapper, 1), (4, 5, 6));
+        assert_eq!(rgb(&mapper, 2), (0, 0, 0));
+        // The data stops in the middle of the last light.
+        assert_eq!(rgb(&mapper, 3), (7, 0, 0));
+        assert_eq!(nick(&mapper, 0).as_deref(), Some("artnet"));
+
+        assert!(matches!(
+            mapper.take_dmx("third", &[1, 2, 3], desk, "artnet"),
+            Err(MapperError::UnknownHost(host)) if host == "third"
+        ));
+    }
+
+    /// Check the order of a message from a source with this IP address.
+    fn check(mapper: &mut Mapper, ip: &str, sequence: u32, timestamp: u64) -> bool {
+        let key = (Some(ip.parse().unwrap()), None);
+        mapper.check_order(key, sequence, timestamp).is_ok()
+    }
+
+    #[test]
+    fn old_sequences_are_dropped() {
+        let mut mapper = mapper("mapping:\n  lights: {}\n").unwrap();
+        assert!(check(&mut mapper, "10.0.0.1", 5, 100));
+        assert!(!check(&mut mapper, "10.0.0.1", 5, 100));
+        assert!(!check(&mut mapper, "10.0.0.1", 4, 100));
+        assert!(check(&mut mapper, "10.0.0.1", 6, 100));
+        // Other sources keep their own order.
+        assert!(check(&mut mapper, "10.0.0.2", 1, 0));
+        assert!(check(&mut mapper, "10.0.0.1", 7, 100));
+    }
+
+    #[test]
+    fn sequences_wrap_around() {
+        let mut mapper = mapper("mapping:\n  lights: {}\n").unwrap();
+        assert!(check(&mut mapper, "10.0.0.1", u32::MAX - 1, 100));
+        assert!(check(&mut mapper, "10.0.0.1", u32::MAX, 100));
+        assert!(check(&mut mapper, "10.0.0.1", 0, 100));
+        assert!(check(&mut mapper, "10.0.0.1", 1, 100));
+        assert!(!check(&mut mapper, "10.0.0.1", u32::MAX, 100));
+    }
+
+    #[test]
+    fn backwards_timestamps_are_dropped() {
+        let mut mapper = mapper("mapping:\n  lights: {}\n").unwrap();
+        assert!(check(&mut mapper, "10.0.0.1", 1, 1000));
+        assert!(!check(&mut mapper, "10.0.0.1", 2, 999));
+        assert!(check(&mut mapper, "10.0.0.1", 2, 1000));
+    }
+
+    #[test]
+    fn quiet_sources_are_reset() {
+        let mut mapper = mapper("mapping:\n  lights: {}\n").unwrap();
+        assert!(check(&mut mapper, "10.0.0.1", 100, 1000));
+        // The client restarted after a while, counting from scratch.
+        for source in mapper.sources.values_mut() {
+            source.seen -= SOURCE_RESET_TIME;
+        }
+        assert!(check(&mut mapper, "10.0.0.1", 1, 0));
+        assert!(!check(&mut mapper, "10.0.0.1", 1, 0));
+    }
+
+    #[test]
+    fn stale_messages_are_not_applied() {
+        let mut mapper = mapper(
+            r#"
+mapping:
+  lights:
+    0: {type: "rgb", host: "first", address: 1}
+"#,
+        )
+        .unwrap();
+        let header = |sequence: u8| vec![2, 0, 0, 0, sequence, 0, 0, 0, 0, 0, 0, 0, 0];
+        let message = |sequence: u8, red: u8| [header(sequence), vec![1, 0, 0, red, 0, 0]].concat();
+        mapper.take_msg(&message(2, 10), None).unwrap();
+        let result = mapper.take_msg(&message(1, 200), None);
+        assert!(matches!(result, Err(MapperError::StaleMessage(1))));
+        assert_eq!(rgb(&mapper, 0), (10, 0, 0));
+    }
+
+    /// A mapper with a single RGB light and some merge settings.
+    fn single_light(merge: &str) -> Mapper {
+        let rest = format!(
+            r#"
+mapping:
+  lights:
+    0: {{type: "rgb", host: "first", address: 1}}
+merge:
+  {}
+"#,
+            merge
+        );
+        mapper(&rest).unwrap()
+    }
+
+    /// Send a light 0 color from an address, a little after the last one.
+    fn send_red(mapper: &mut Mapper, from: &str, red: u8) {
+        std::thread::sleep(Duration::from_millis(1));
+        mapper.take_msg(&[1, 1, 0, 0, red, 0, 0], Some(from.parse().unwrap())).unwrap();
+    }
+
+    #[test]
+    fn htp_and_priorities() {
+        let mut mapper = single_light("{mode: htp, sources: [{ip: 10.0.0.3, priority: 200}]}");
+        send_red(&mut mapper, "10.0.0.1", 10);
+        send_red(&mut mapper, "10.0.0.2", 200);
+        send_red(&mut mapper, "10.0.0.1", 20);
+        assert_eq!(mapper.lights[&0].values.red, 200);
+        // A higher priority wins even when it's lower.
+        send_red(&mut mapper, "10.0.0.3", 5);
+        assert_eq!(mapper.lights[&0].values.red, 5);
+    }
+
+    #[test]
+    fn release_reveals_what_is_underneath() {
+        let mut mapper = single_light("releaseTimeout: 1");
+        send_red(&mut mapper, "10.0.0.1", 10);
+        send_red(&mut mapper, "10.0.0.2", 200);
+        let source = (Some("10.0.0.2".parse().unwrap()), None);
+        mapper.lights.get_mut(&0).unwrap().release(&source, MergeMode::Ltp);
+        assert_eq!(mapper.lights[&0].values.red, 10);
+
+        mapper.release_inactive_sources(Instant::now());
+        assert_eq!(mapper.lights[&0].values.red, 10);
+        mapper.release_inactive_sources(Instant::now() + Duration::from_secs(2));
+        assert_eq!(mapper.lights[&0].values.red, 0);
+        assert!(mapper.lights[&0].layers.is_empty());
+    }
+
+    #[test]
+    fn cues_only_replace_their_own_effects() {
+        let mut mapper = single_light("mode: ltp");
+        mapper.cues = CueList::new(vec![
+            Cue {
+                name: None,
+                scene: None,
+                fade: 0.0,
+                effects: Some(vec!["strobe".to_owned()]),
+                wait: None,
+            },
+            Cue {
+                name: None,
+                scene: None,
+                fade: 0.0,
+                effects: Some(vec!["fire".to_owned()]),
+                wait: None,
+            },
+        ]);
+        let ip = Some("10.0.0.1".parse().unwrap());
+        mapper.start_effect("wave", None, None, ip, None).unwrap();
+
+        mapper.go_cue();
+        mapper.go_cue();
+        let mut names: Vec<String> = mapper
+            .effects_info()
+            .into_iter()
+            .map(|info| info.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["fire", "wave"]);
+    }
+    #[test]
+    fn repeated_dmx_doesnt_take_the_light_back() {
+        let mut mapper = single_light("mode: ltp");
+        let desk = |mapper: &mut Mapper, red: u8| {
+            std::thread::sleep(Duration::from_millis(1));
+            let ip = Some("10.0.0.9".parse().unwrap());
+            mapper
+                .take_dmx("first", &[red, 0, 0], ip, "artnet")
+                .unwrap();
+        };
+        desk(&mut mapper, 10);
+        send_red(&mut mapper, "10.0.0.1", 200);
+        desk(&mut mapper, 10);
+        assert_eq!(mapper.lights[&0].values.red, 200);
+
+        desk(&mut mapper, 30);
+        assert_eq!(mapper.lights[&0].values.red, 30);
+    }
+    #[test]
+    fn dimmers_fade_their_intensity() {
+        let mut mapper = mapper(
+            r#"
+mapping:
+  lights:
+    0: {type: "dimmer", host: "first", address: 1}
+"#,
+        )
+        .unwrap();
+        mapper
+            .take_msg(&[1, 1, 0, 0, 255, 255, 255], None)
+            .unwrap();
+        // Fade to 100 in 100 ms.
+        let start = Instant::now();
+        mapper
+            .take_msg(&[1, 3, 0, 100, 50, 0, 0, 100, 0], None)
+            .unwrap();
+        let light = mapper.lights.get_mut(&0).unwrap();
+
+        light.step_fades(start + Duration::from_millis(50), MergeMode::Ltp);
+        let dimmer = light.output_values(1.0).dimmer;
+        assert!(dimmer > 100 && dimmer < 255, "{}", dimmer);
+        light.step_fades(start + Duration::from_secs(1), MergeMode::Ltp);
+        assert_eq!(light.values.dimmer, 100);
+    }
+    #[test]
+    fn recalled_scene_doesnt_take_the_light_back() {
+        let mut mapper = single_light("mode: ltp");
+        let red = LightValues {
+            red: 50,
+            ..LightValues::initial(LightType::Rgb)
+        };
+        let scene = [(0, red)].iter().cloned().collect();
+        mapper.scenes.insert("red".to_owned(), scene);
+        mapper.recall_scene("red", Duration::ZERO).unwrap();
+        mapper.run_scene(Instant::now());
+        assert_eq!(mapper.lights[&0].values.red, 50);
+
+        send_red(&mut mapper, "10.0.0.1", 200);
+        mapper.run_scene(Instant::now());
+        assert_eq!(mapper.lights[&0].values.red, 200);
+        assert_eq!(mapper.scenes_info().active.as_deref(), Some("red"));
+
+        // Releasing the scene still lets go of its layer.
+        mapper.release_scene();
+        assert_eq!(mapper.lights[&0].layers.len(), 1);
+    }
 }
@@ -0,0 +1,139 @@
+//! Scenes: snapshots of every light's state that can be recalled later.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use super::{LightChange, LightValues};
+
+/// Channel values of every light by logical address.
+pub type Scene = BTreeMap<u8, LightValues>;
+
+/// Scenes by name.
+pub type Scenes = BTreeMap<String, Scene>;
+
+/// Read scenes from a YAML file. A missing file has no scenes.
+pub fn load(path: &Path) -> io::Result<Scenes> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Scenes::new()),
+        Err(err) => return Err(err),
+    };
+    serde_yaml::from_reader(io::BufReader::new(file))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Write scenes to a YAML file.
+///
+/// The scenes go to a temporary file next to it first, which then replaces
+/// the file, so a crash halfway through can't leave it cut short.
+pub fn save(path: &Path, scenes: &Scenes) -> io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = io::BufWriter::new(fs::File::create(&temp_path)?);
+    serde_yaml::to_writer(&mut file, scenes).map_err(io::Error::other)?;
+    file.into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// A scene being recalled.
+pub struct Recall {
+    pub name: String,
+    /// Values of the lights when the recall started.
+    pub from: HashMap<u8, LightValues>,
+    pub start: Instant,
+    /// Crossfade duration.
+    pub duration: Duration,
+}
+
+impl Recall {
+    /// How far along the crossfade is, from 0 to 1.
+    pub fn progress(&self, now: Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.duration {
+            1.0
+        } else {
+            elapsed.as_secs_f32() / self.duration.as_secs_f32()
+        }
+    }
+}
+
+/// Get the values part of the way from one state to another.
+///
+/// Colors and intensities are crossfaded. Pan and tilt move along, while
+/// things like gobos just snap to their new positions.
+pub fn mix(from: &LightValues, to: &LightValues, t: f32) -> LightChange {
+    let mix8 = |from: u8, to: u8| {
+        (f32::from(from) + (f32::from(to) - f32::from(from)) * t).round() as u8
+    };
+    let mix16 = |from: u16, to: u16| {
+        (f32::from(from) + (f32::from(to) - f32::from(from)) * t).round() as u16
+    };
+    let [from_red, from_green, from_blue] = from.color16();
+    let [to_red, to_green, to_blue] = to.color16();
+    LightChange {
+        white: Some(mix8(from.white, to.white)),
+        amber: Some(mix8(from.amber, to.amber)),
+        dimmer: Some(mix8(from.dimmer, to.dimmer)),
+        pan: Some(mix16(from.pan, to.pan)),
+        tilt: Some(mix16(from.tilt, to.tilt)),
+        color_wheel: Some(to.color_wheel),
+        gobo: Some(to.gobo),
+        shutter: Some(to.shutter),
+        ..LightChange::rgb16(
+            mix16(from_red, to_red),
+            mix16(from_green, to_green),
+            mix16(from_blue, to_blue),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for a test.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("scene-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = test_dir("save");
+        let path = dir.join("scenes.yaml");
+        assert!(load(&path).unwrap().is_empty());
+
+        let red = LightValues {
+            red: 255,
+            dimmer: 255,
+            ..LightValues::default()
+        };
+        let mut scenes = Scenes::new();
+        scenes.insert("red".to_owned(), [(0, red)].iter().cloned().collect());
+        save(&path, &scenes).unwrap();
+        scenes.insert("empty".to_owned(), Scene::new());
+        save(&path, &scenes).unwrap();
+
+        assert_eq!(load(&path).unwrap(), scenes);
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1, "temporary file left behind");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn malformed_file_is_an_error() {
+        let dir = test_dir("malformed");
+        let path = dir.join("scenes.yaml");
+        fs::write(&path, "red: [").unwrap();
+        let err = load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
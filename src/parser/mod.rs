@@ -1,20 +1,146 @@
-//! Effect server v1 message format parser.
+//! Effect server message format parser.
+//!
+//! Messages start with a version byte. Version 1 messages go straight to
+//! the commands. Version 2 messages have a header before the commands:
+//! a 32-bit sequence number and a 64-bit sender timestamp in milliseconds,
+//! both big-endian. The commands are the same in both versions.
 
 use std::io::{self, Read};
-use byteorder::ReadBytesExt;
+use byteorder::{BigEndian, ReadBytesExt};
 
 /// Command buffer and message parser.
 pub struct CommandParser {
+    /// Header of the last message.
+    pub header: Header,
     /// Commands parsed so far.
     pub cmds: Vec<Command>,
 }
 
+/// Message header.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Header {
+    /// Protocol version.
+    pub version: u8,
+    /// Sender's sequence number, on v2 and up.
+    pub sequence: Option<u32>,
+    /// Sender's timestamp in milliseconds, on v2 and up.
+    pub timestamp: Option<u64>,
+}
+
 /// Abstract commands recognized by the command mapper.
+#[derive(Debug, PartialEq)]
 pub enum Command {
     /// Set nick for the next commands.
     Nick { nick: String },
     /// Set an RGB light's state.
     RgbLight { id: u8, light_type: u8, red: u8, green: u8, blue: u8 },
+    /// Set an RGB light's color with 16 bits per channel.
+    Rgb16Light { id: u8, red: u16, green: u16, blue: u16 },
+    /// Set an RGB light's color by hue, saturation and value.
+    HsvLight {
+        id: u8,
+        /// Hue around the whole color circle, starting and ending at red.
+        hue: u16,
+        saturation: u8,
+        value: u8,
+    },
+    /// Set an RGBW light's state.
+    RgbwLight { id: u8, red: u8, green: u8, blue: u8, white: u8 },
+    /// Set an RGBA light's state.
+    RgbaLight { id: u8, red: u8, green: u8, blue: u8, amber: u8 },
+    /// Set a dimmer's intensity, or the master dimmer of a color light.
+    DimmerLight { id: u8, level: u8 },
+    /// Switch a relay on or off.
+    RelayLight { id: u8, on: bool },
+    /// Set a moving head's position and beam.
+    Position {
+        id: u8,
+        /// 16-bit pan position.
+        pan: u16,
+        /// 16-bit tilt position.
+        tilt: u16,
+        color_wheel: u8,
+        gobo: u8,
+        shutter: u8,
+    },
+    /// Set the master intensity of a group, or the grand master if the
+    /// group name is empty.
+    Master { group: String, level: u8 },
+    /// Start or stop a named effect. Stopping the effect with an empty
+    /// name stops every effect.
+    Effect { name: String, start: bool },
+    /// Do something with a scene, one of the `scene_action` constants.
+    Scene {
+        action: u8,
+        name: String,
+        /// Crossfade duration in milliseconds, when recalling.
+        duration: u16,
+    },
+    /// Control cue list playback, one of the `cue_action` constants.
+    Cue { action: u8 },
+    /// Fade an RGB light to a color.
+    Fade {
+        id: u8,
+        red: u8,
+        green: u8,
+        blue: u8,
+        /// Fade duration in milliseconds.
+        duration: u16,
+        /// Easing curve, one of the `easing` constants.
+        easing: u8,
+    },
+}
+
+/// Light types in light commands. They decide what follows the type byte.
+pub mod light_type {
+    /// Red, green, blue.
+    pub const RGB: u8 = 0;
+    /// Red, green, blue, white.
+    pub const RGBW: u8 = 1;
+    /// Red, green, blue, amber.
+    pub const RGBA: u8 = 2;
+    /// Intensity.
+    pub const DIMMER: u8 = 3;
+    /// Zero for off, anything else for on.
+    pub const RELAY: u8 = 4;
+    /// Hue (16 bits), saturation, value.
+    pub const HSV: u8 = 5;
+    /// Red, green, blue, 16 bits each.
+    pub const RGB16: u8 = 6;
+}
+
+/// Easing curves in fade commands.
+pub mod easing {
+    /// Constant speed.
+    pub const LINEAR: u8 = 0;
+    /// Start slow, end fast.
+    pub const EASE_IN: u8 = 1;
+    /// Start fast, end slow.
+    pub const EASE_OUT: u8 = 2;
+    /// Start and end slow.
+    pub const EASE_IN_OUT: u8 = 3;
+}
+
+/// Actions in scene commands.
+pub mod scene_action {
+    /// Recall the scene, with a crossfade.
+    pub const RECALL: u8 = 0;
+    /// Store the current state of the lights as the scene.
+    pub const STORE: u8 = 1;
+    /// Let go of the recalled scene, whatever the name.
+    pub const RELEASE: u8 = 2;
+}
+
+/// Actions in cue commands.
+pub mod cue_action {
+    /// Go to the next cue.
+    pub const GO: u8 = 0;
+    /// Go back to the previous cue.
+    pub const BACK: u8 = 1;
+    /// Hold the wait before the next cue.
+    pub const PAUSE: u8 = 2;
+    /// Continue a held wait.
+    pub const RESUME: u8 = 3;
 }
 
 pub type ParserResult<T> = Result<T, ParserError>;
@@ -23,6 +149,9 @@ pub type ParserResult<T> = Result<T, ParserError>;
 pub enum ParserError {
     InvalidProtocolVersion(u8),
     UnsupportedLightType(u8),
+    UnsupportedEasing(u8),
+    UnsupportedSceneAction(u8),
+    UnsupportedCueAction(u8),
     UnknownCommand(u8),
     // InvalidNick,
     IoError(io::Error),
@@ -34,14 +163,21 @@ impl From<io::Error> for ParserError {
     }
 }
 
+impl Default for CommandParser {
+    fn default() -> CommandParser {
+        CommandParser::new()
+    }
+}
+
 impl CommandParser {
     pub fn new() -> CommandParser {
         CommandParser {
+            header: Header::default(),
             cmds: vec![],
         }
     }
 
-    pub fn read_from(&mut self, buf: &mut Read) -> ParserResult<()> {
+    pub fn read_from(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
         // Clear temp command buffer.
         self.cmds.clear();
         // Check the header.
@@ -50,21 +186,37 @@ impl CommandParser {
         Ok(())
     }
 
-    ///
-    fn read_header(&mut self, buf: &mut Read) -> ParserResult<()> {
-        match buf.read_u8() {
-            Ok(1) => Ok(()),
-            Ok(ver) => Err(ParserError::InvalidProtocolVersion(ver)),
-            Err(io_error) => Err(ParserError::IoError(io_error)),
-        }
+    /// Read and check the protocol version header.
+    fn read_header(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
+        self.header = match buf.read_u8() {
+            Ok(1) => Header {
+                version: 1,
+                sequence: None,
+                timestamp: None,
+            },
+            Ok(2) => Header {
+                version: 2,
+                sequence: Some(buf.read_u32::<BigEndian>()?),
+                timestamp: Some(buf.read_u64::<BigEndian>()?),
+            },
+            Ok(ver) => return Err(ParserError::InvalidProtocolVersion(ver)),
+            Err(io_error) => return Err(ParserError::IoError(io_error)),
+        };
+        Ok(())
     }
 
     /// Read the next command, if there is any data left. Returns Ok(false) on end-of-data.
-    fn read_cmd(&mut self, buf: &mut Read) -> ParserResult<bool> {
+    fn read_cmd(&mut self, buf: &mut dyn Read) -> ParserResult<bool> {
         // Read cmd tag
         match buf.read_u8() {
             Ok(0) => self.read_cmd_nick(buf),
             Ok(1) => self.read_cmd_light(buf),
+            Ok(2) => self.read_cmd_position(buf),
+            Ok(3) => self.read_cmd_fade(buf),
+            Ok(4) => self.read_cmd_master(buf),
+            Ok(5) => self.read_cmd_effect(buf),
+            Ok(6) => self.read_cmd_scene(buf),
+            Ok(7) => self.read_cmd_cue(buf),
             Ok(cmd) => Err(ParserError::UnknownCommand(cmd)),
             Err(io_error) => match io_error.kind() {
                 io::ErrorKind::UnexpectedEof => {
@@ -74,43 +226,337 @@ impl CommandParser {
                 _ => return Err(ParserError::IoError(io_error)),
             },
         }?;
-        return Ok(true);
+        Ok(true)
     }
 
     /// Read a nickname command.
-    fn read_cmd_nick(&mut self, buf: &mut Read) -> ParserResult<()> {
-        let mut tmp = vec![];
-        // Read bytes until we hit a zero
-        loop {
-            let byte = buf.read_u8()?;
-            if byte == 0 {
-                break;
-            }
-            tmp.push(byte);
+    fn read_cmd_nick(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
+        let nick = read_string(buf)?;
+        self.cmds.push(Command::Nick { nick });
+        Ok(())
+    }
+
+    /// Read an effect command: an effect name and 1 to start or 0 to stop.
+    fn read_cmd_effect(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
+        let name = read_string(buf)?;
+        let start = buf.read_u8()? != 0;
+        self.cmds.push(Command::Effect { name, start });
+        Ok(())
+    }
+
+    /// Read a scene command: an action, a scene name and a big-endian
+    /// 16-bit crossfade duration in milliseconds.
+    fn read_cmd_scene(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
+        let action = buf.read_u8()?;
+        if action > scene_action::RELEASE {
+            return Err(ParserError::UnsupportedSceneAction(action));
         }
+        let name = read_string(buf)?;
+        let duration = buf.read_u16::<BigEndian>()?;
+        self.cmds.push(Command::Scene { action, name, duration });
+        Ok(())
+    }
 
-        // Let's just tolerate bad UTF-8 for now.
-        let nick = String::from_utf8_lossy(&tmp).to_string();
-        self.cmds.push(Command::Nick { nick });
+    /// Read a cue command: an action.
+    fn read_cmd_cue(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
+        let action = buf.read_u8()?;
+        if action > cue_action::RESUME {
+            return Err(ParserError::UnsupportedCueAction(action));
+        }
+        self.cmds.push(Command::Cue { action });
+        Ok(())
+    }
+
+    /// Read a master command: a group name and a level.
+    fn read_cmd_master(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
+        let group = read_string(buf)?;
+        let level = buf.read_u8()?;
+        self.cmds.push(Command::Master { group, level });
         Ok(())
     }
 
     /// Read a basic light command from the buffer.
-    fn read_cmd_light(&mut self, buf: &mut Read) -> ParserResult<()> {
+    ///
+    /// 16-bit values are big-endian.
+    fn read_cmd_light(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
         let id = buf.read_u8()?;
 
         let light_type = buf.read_u8()?;
-        if light_type != 0 {
-            return Err(ParserError::UnsupportedLightType(light_type));
-        }
+        let cmd = match light_type {
+            light_type::RGB => Command::RgbLight {
+                id,
+                light_type,
+                red: buf.read_u8()?,
+                green: buf.read_u8()?,
+                blue: buf.read_u8()?,
+            },
+            light_type::RGBW => Command::RgbwLight {
+                id,
+                red: buf.read_u8()?,
+                green: buf.read_u8()?,
+                blue: buf.read_u8()?,
+                white: buf.read_u8()?,
+            },
+            light_type::RGBA => Command::RgbaLight {
+                id,
+                red: buf.read_u8()?,
+                green: buf.read_u8()?,
+                blue: buf.read_u8()?,
+                amber: buf.read_u8()?,
+            },
+            light_type::DIMMER => Command::DimmerLight {
+                id,
+                level: buf.read_u8()?,
+            },
+            light_type::RELAY => Command::RelayLight {
+                id,
+                on: buf.read_u8()? != 0,
+            },
+            light_type::HSV => Command::HsvLight {
+                id,
+                hue: buf.read_u16::<BigEndian>()?,
+                saturation: buf.read_u8()?,
+                value: buf.read_u8()?,
+            },
+            light_type::RGB16 => Command::Rgb16Light {
+                id,
+                red: buf.read_u16::<BigEndian>()?,
+                green: buf.read_u16::<BigEndian>()?,
+                blue: buf.read_u16::<BigEndian>()?,
+            },
+            _ => return Err(ParserError::UnsupportedLightType(light_type)),
+        };
+
+        self.cmds.push(cmd);
+        Ok(())
+    }
 
-        self.cmds.push(Command::RgbLight {
-            id,
-            light_type,
-            red: buf.read_u8()?,
-            green: buf.read_u8()?,
-            blue: buf.read_u8()?,
+    /// Read a position command from the buffer.
+    ///
+    /// Pan and tilt are big-endian 16-bit values, followed by the color
+    /// wheel, gobo and shutter bytes.
+    fn read_cmd_position(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
+        self.cmds.push(Command::Position {
+            id: buf.read_u8()?,
+            pan: buf.read_u16::<BigEndian>()?,
+            tilt: buf.read_u16::<BigEndian>()?,
+            color_wheel: buf.read_u8()?,
+            gobo: buf.read_u8()?,
+            shutter: buf.read_u8()?,
         });
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Read a fade command from the buffer.
+    ///
+    /// The target color is followed by a big-endian 16-bit duration in
+    /// milliseconds and an easing curve byte.
+    fn read_cmd_fade(&mut self, buf: &mut dyn Read) -> ParserResult<()> {
+        let id = buf.read_u8()?;
+        let red = buf.read_u8()?;
+        let green = buf.read_u8()?;
+        let blue = buf.read_u8()?;
+        let duration = buf.read_u16::<BigEndian>()?;
+        let easing = buf.read_u8()?;
+        if easing > easing::EASE_IN_OUT {
+            return Err(ParserError::UnsupportedEasing(easing));
+        }
+        self.cmds.push(Command::Fade { id, red, green, blue, duration, easing });
+        Ok(())
+    }
+}
+
+/// Read a zero-terminated string.
+fn read_string(buf: &mut dyn Read) -> ParserResult<String> {
+    let mut tmp = vec![];
+    // Read bytes until we hit a zero
+    loop {
+        let byte = buf.read_u8()?;
+        if byte == 0 {
+            break;
+        }
+        tmp.push(byte);
+    }
+
+    // Let's just tolerate bad UTF-8 for now.
+    Ok(String::from_utf8_lossy(&tmp).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a message, panicking on errors.
+    fn parse(msg: &[u8]) -> CommandParser {
+        let mut parser = CommandParser::new();
+        parser.read_from(&mut &msg[..]).unwrap();
+        parser
+    }
+
+    fn parse_err(msg: &[u8]) -> ParserError {
+        let mut parser = CommandParser::new();
+        match parser.read_from(&mut &msg[..]) {
+            Ok(()) => panic!("{:?} parsed to {:?}", msg, parser.cmds),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn v1_header() {
+        let parser = parse(&[1]);
+        assert_eq!(parser.header, Header { version: 1, sequence: None, timestamp: None });
+        assert!(parser.cmds.is_empty());
+    }
+
+    #[test]
+    fn v2_header() {
+        let mut msg = vec![2, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0x10, 0x20];
+        msg.extend_from_slice(&[1, 3, light_type::RGB, 10, 20, 30]);
+        let parser = parse(&msg);
+        assert_eq!(
+            parser.header,
+            Header { version: 2, sequence: Some(0x0102), timestamp: Some(0x1020) }
+        );
+        assert_eq!(
+            parser.cmds,
+            vec![Command::RgbLight { id: 3, light_type: 0, red: 10, green: 20, blue: 30 }]
+        );
+    }
+
+    #[test]
+    fn invalid_headers() {
+        assert!(matches!(parse_err(&[3]), ParserError::InvalidProtocolVersion(3)));
+        assert!(matches!(parse_err(&[]), ParserError::IoError(_)));
+        // Cut off in the middle of the v2 header.
+        assert!(matches!(parse_err(&[2, 0, 0]), ParserError::IoError(_)));
+    }
+
+    #[test]
+    fn nick() {
+        let parser = parse(b"\x01\x00vj\x00");
+        assert_eq!(parser.cmds, vec![Command::Nick { nick: "vj".to_owned() }]);
+    }
+
+    #[test]
+    fn light_types() {
+        let msg = [
+            1,
+            1, 0, light_type::RGB, 1, 2, 3,
+            1, 1, light_type::RGBW, 1, 2, 3, 4,
+            1, 2, light_type::RGBA, 1, 2, 3, 5,
+            1, 3, light_type::DIMMER, 6,
+            1, 4, light_type::RELAY, 7,
+            1, 5, light_type::HSV, 0x80, 0x00, 255, 128,
+            1, 6, light_type::RGB16, 0xff, 0xfe, 0, 1, 0x12, 0x34,
+        ];
+        assert_eq!(
+            parse(&msg).cmds,
+            vec![
+                Command::RgbLight { id: 0, light_type: 0, red: 1, green: 2, blue: 3 },
+                Command::RgbwLight { id: 1, red: 1, green: 2, blue: 3, white: 4 },
+                Command::RgbaLight { id: 2, red: 1, green: 2, blue: 3, amber: 5 },
+                Command::DimmerLight { id: 3, level: 6 },
+                Command::RelayLight { id: 4, on: true },
+                Command::HsvLight { id: 5, hue: 0x8000, saturation: 255, value: 128 },
+                Command::Rgb16Light { id: 6, red: 0xfffe, green: 1, blue: 0x1234 },
+            ]
+        );
+        assert!(matches!(parse_err(&[1, 1, 0, 7]), ParserError::UnsupportedLightType(7)));
+    }
+
+    #[test]
+    fn unknown_and_truncated_commands() {
+        assert!(matches!(parse_err(&[1, 8]), ParserError::UnknownCommand(8)));
+        // A command cut off in the middle is an error, unlike running out
+        // of data between commands.
+        assert!(matches!(parse_err(&[1, 1, 0, light_type::RGB, 1]), ParserError::IoError(_)));
+    }
+
+    #[test]
+    fn position() {
+        let parser = parse(&[1, 2, 9, 0x12, 0x34, 0x56, 0x78, 1, 2, 3]);
+        assert_eq!(
+            parser.cmds,
+            vec![Command::Position {
+                id: 9,
+                pan: 0x1234,
+                tilt: 0x5678,
+                color_wheel: 1,
+                gobo: 2,
+                shutter: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn fade() {
+        let parser = parse(&[1, 3, 2, 10, 20, 30, 0x01, 0xf4, easing::EASE_OUT]);
+        assert_eq!(
+            parser.cmds,
+            vec![Command::Fade {
+                id: 2,
+                red: 10,
+                green: 20,
+                blue: 30,
+                duration: 500,
+                easing: easing::EASE_OUT,
+            }]
+        );
+        let msg = [1, 3, 2, 10, 20, 30, 0, 0, 4];
+        assert!(matches!(parse_err(&msg), ParserError::UnsupportedEasing(4)));
+    }
+
+    #[test]
+    fn master() {
+        let parser = parse(b"\x01\x04left\x00\x80\x04\x00\xff");
+        assert_eq!(
+            parser.cmds,
+            vec![
+                Command::Master { group: "left".to_owned(), level: 128 },
+                Command::Master { group: String::new(), level: 255 },
+            ]
+        );
+    }
+
+    #[test]
+    fn effect() {
+        let parser = parse(b"\x01\x05fire\x00\x01\x05\x00\x00");
+        assert_eq!(
+            parser.cmds,
+            vec![
+                Command::Effect { name: "fire".to_owned(), start: true },
+                Command::Effect { name: String::new(), start: false },
+            ]
+        );
+    }
+
+    #[test]
+    fn scene() {
+        let parser = parse(b"\x01\x06\x00red\x00\x03\xe8\x06\x02\x00\x00\x00");
+        assert_eq!(
+            parser.cmds,
+            vec![
+                Command::Scene {
+                    action: scene_action::RECALL,
+                    name: "red".to_owned(),
+                    duration: 1000,
+                },
+                Command::Scene { action: scene_action::RELEASE, name: String::new(), duration: 0 },
+            ]
+        );
+        assert!(matches!(parse_err(b"\x01\x06\x03"), ParserError::UnsupportedSceneAction(3)));
+    }
+
+    #[test]
+    fn cue() {
+        let parser = parse(&[1, 7, cue_action::GO, 7, cue_action::RESUME]);
+        assert_eq!(
+            parser.cmds,
+            vec![
+                Command::Cue { action: cue_action::GO },
+                Command::Cue { action: cue_action::RESUME },
+            ]
+        );
+        assert!(matches!(parse_err(&[1, 7, 4]), ParserError::UnsupportedCueAction(4)));
+    }
+}
@@ -0,0 +1,85 @@
+//! Art-Net and sACN (E1.31) inputs.
+//!
+//! These let a lighting desk drive the lights as if the server were a plain
+//! DMX node, while other clients keep using the v1 protocol.
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+
+use super::{ServerMessage, MAX_PACKET_SIZE};
+use crate::config::DmxInput;
+use crate::host::{artnet, sacn};
+
+/// Protocol name of Art-Net input, also used as its sources' nick.
+pub(super) const ARTNET: &str = "artnet";
+/// Protocol name of sACN input, also used as its sources' nick.
+pub(super) const SACN: &str = "sacn";
+
+/// Reads the universe and channel data from a packet, if it has any.
+type PacketParser = fn(&[u8]) -> Option<(u16, &[u8])>;
+
+/// Start a thread that accepts ArtDMX packets and messages them
+/// to the server's event loop.
+pub(super) fn start_artnet_thread(input: DmxInput, sender: Sender<ServerMessage>) -> JoinHandle<()> {
+    println!("[artnet] Starting Art-Net input at {}", input.addr);
+    let socket = UdpSocket::bind(&input.addr).expect("[artnet] Unable to create UDP socket!");
+
+    thread::spawn(move || receive_loop(socket, input, sender, ARTNET, artnet::parse_dmx))
+}
+
+/// Start a thread that accepts sACN data packets and messages them
+/// to the server's event loop.
+pub(super) fn start_sacn_thread(input: DmxInput, sender: Sender<ServerMessage>) -> JoinHandle<()> {
+    println!("[sacn] Starting sACN input at {}", input.addr);
+    let socket = UdpSocket::bind(&input.addr).expect("[sacn] Unable to create UDP socket!");
+    // Sources usually multicast, so join the groups of the universes we want.
+    for universe in input.universes.keys() {
+        let group = sacn::multicast_addr(*universe);
+        if let Err(err) = socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED) {
+            eprintln!("[sacn] Unable to join multicast group {}: {:?}", group.ip(), err);
+        }
+    }
+
+    thread::spawn(move || receive_loop(socket, input, sender, SACN, sacn::parse_data))
+}
+
+/// Receive packets, pass the channel data of the configured universes on.
+fn receive_loop(
+    socket: UdpSocket,
+    input: DmxInput,
+    sender: Sender<ServerMessage>,
+    protocol: &'static str,
+    parse: PacketParser,
+) {
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+        let (len, source) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) => {
+                eprintln!("[{}] Unable to receive: {:?}", protocol, err);
+                continue;
+            }
+        };
+        let (universe, channels) = match parse(&buf[0..len]) {
+            Some(data) => data,
+            None => continue,
+        };
+        let host = match input.universes.get(&universe) {
+            Some(host) => host.clone(),
+            None => continue,
+        };
+
+        let message = ServerMessage::Dmx {
+            ip: source.ip(),
+            protocol,
+            host,
+            channels: channels.to_owned(),
+            replayed: false,
+        };
+        if sender.send(message).is_err() {
+            eprintln!("[{}] Packet receiver gone. Exiting thread.", protocol);
+            return;
+        }
+    }
+}
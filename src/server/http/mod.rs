@@ -0,0 +1,336 @@
+//! HTTP JSON API and the control panel page.
+//!
+//! Routes:
+//!
+//! - `GET /` serves the control panel.
+//! - `GET /api/lights` lists every light.
+//! - `GET /api/lights/<id>` gets a single light.
+//! - `PUT /api/lights` sets many lights: `{"nick": "...", "lights": [{"id": 0, "red": 255, ...}]}`
+//! - `PUT /api/lights/<id>` sets a single light: `{"red": 255, "green": 0, "blue": 0}`
+//! - `GET /api/masters` gets the grand master and group master levels.
+//! - `PUT /api/masters` sets some of them: `{"grandMaster": 128, "groups": {"left": 255}}`
+//! - `GET /api/effects` lists the running effects.
+//! - `PUT /api/effects` starts an effect: `{"name": "...", "effect": {"type": "strobe", "rate": 5}}`
+//! - `DELETE /api/effects/<name>` stops an effect, and `DELETE /api/effects` stops them all.
+//! - `GET /api/scenes` and `GET /api/scene` list the stored scenes and the recalled one.
+//! - `PUT /api/scenes/<name>` stores the current state of the lights as a scene.
+//! - `DELETE /api/scenes/<name>` deletes a scene.
+//! - `PUT /api/scene` recalls a scene with a crossfade: `{"name": "...", "fade": 2.5}`
+//! - `DELETE /api/scene` releases the recalled scene.
+//! - `GET /api/cues` gets the cue list and where playback is at.
+//! - `PUT /api/cues/go`, `/back`, `/pause` and `/resume` control cue list playback.
+//! - `GET /api/hosts` lists the configured hosts.
+//! - `GET /api/info` tells where the other servers are.
+//!
+//! `POST` works as well as `PUT`. Names in paths are percent-decoded, so
+//! `/api/scenes/front%20wash` is the scene "front wash".
+
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use super::ServerMessage;
+use crate::api::{
+    ApiRequest, ApiResponse, LightUpdate, RecallSceneBody, SetLightsBody, SetMastersBody,
+    StartEffectBody,
+};
+use crate::config::Root;
+
+/// The control panel page.
+const INDEX_HTML: &str = include_str!("index.html");
+
+/// Static information about the server for API clients.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ServerInfo {
+    udp_addr: String,
+    websocket_addr: String,
+}
+
+/// Things that don't change while the server is running.
+struct StaticData {
+    hosts: serde_json::Value,
+    info: ServerInfo,
+}
+
+/// Error response body.
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    error: String,
+}
+
+/// An HTTP response that hasn't been sent yet.
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Reply {
+    fn json<T: Serialize>(status: u16, value: &T) -> Reply {
+        match serde_json::to_string(value) {
+            Ok(body) => Reply {
+                status,
+                content_type: "application/json",
+                body,
+            },
+            Err(err) => Reply::error(500, format!("{:?}", err)),
+        }
+    }
+
+    fn error(status: u16, error: String) -> Reply {
+        Reply::json(status, &ErrorBody { error })
+    }
+
+    fn html(body: &str) -> Reply {
+        Reply {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: body.to_owned(),
+        }
+    }
+}
+
+/// Start a thread that serves the HTTP API and passes its requests
+/// to the server's event loop.
+pub(super) fn start_http_thread(config: &Root, sender: Sender<ServerMessage>) -> JoinHandle<()> {
+    let web_addr = &config.server.web_addr;
+    println!("[http] Starting HTTP server at {}", web_addr);
+    let server = Server::http(web_addr).expect("[http] Unable to create HTTP server!");
+
+    let data = StaticData {
+        hosts: serde_json::to_value(&config.hosts).expect("Unable to serialize hosts"),
+        info: ServerInfo {
+            udp_addr: config.server.udp_addr.clone(),
+            websocket_addr: config.server.websocket_addr.clone(),
+        },
+    };
+
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let reply = route(&mut request, &data, &sender);
+            let header = Header::from_bytes(&b"Content-Type"[..], reply.content_type.as_bytes())
+                .expect("Invalid header");
+            let response = Response::from_string(reply.body)
+                .with_status_code(reply.status)
+                .with_header(header);
+            if let Err(err) = request.respond(response) {
+                eprintln!("[http] Unable to respond: {:?}", err);
+            }
+        }
+    })
+}
+
+/// Figure out what a request wants and get a reply for it.
+fn route(request: &mut Request, data: &StaticData, sender: &Sender<ServerMessage>) -> Reply {
+    let ip = request.remote_addr().map(|addr| addr.ip());
+    let path = request.url().split('?').next().unwrap_or("").to_owned();
+    let segments: Option<Vec<String>> = path
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect();
+    let segments = match segments {
+        Some(segments) => segments,
+        None => return Reply::error(400, format!("Invalid path {}", path)),
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let method = request.method().clone();
+    let is_set = method == Method::Put || method == Method::Post;
+
+    match (segments.as_slice(), method) {
+        ([], Method::Get) | (["index.html"], Method::Get) => Reply::html(INDEX_HTML),
+        (["api", "hosts"], Method::Get) => Reply::json(200, &data.hosts),
+        (["api", "info"], Method::Get) => Reply::json(200, &data.info),
+        (["api", "lights"], Method::Get) => call_api(sender, ApiRequest::GetLights),
+        (["api", "lights", id], Method::Get) => match id.parse() {
+            Ok(id) => call_api(sender, ApiRequest::GetLight { id }),
+            Err(_) => Reply::error(404, format!("Invalid light id {}", id)),
+        },
+        (["api", "lights"], _) if is_set => match read_json::<SetLightsBody>(request) {
+            Ok(body) => call_api(
+                sender,
+                ApiRequest::SetLights {
+                    ip,
+                    nick: body.nick,
+                    lights: body.lights,
+                },
+            ),
+            Err(reply) => reply,
+        },
+        (["api", "lights", id], _) if is_set => {
+            let id: u8 = match id.parse() {
+                Ok(id) => id,
+                Err(_) => return Reply::error(404, format!("Invalid light id {}", id)),
+            };
+            match read_json::<LightUpdate>(request) {
+                Ok(mut update) => {
+                    update.id = Some(id);
+                    call_api(
+                        sender,
+                        ApiRequest::SetLights {
+                            ip,
+                            nick: None,
+                            lights: vec![update],
+                        },
+                    )
+                }
+                Err(reply) => reply,
+            }
+        }
+        (["api", "masters"], Method::Get) => call_api(sender, ApiRequest::GetMasters),
+        (["api", "masters"], _) if is_set => match read_json::<SetMastersBody>(request) {
+            Ok(body) => call_api(
+                sender,
+                ApiRequest::SetMasters {
+                    grand_master: body.grand_master,
+                    groups: body.groups,
+                },
+            ),
+            Err(reply) => reply,
+        },
+        (["api", "effects"], Method::Get) => call_api(sender, ApiRequest::GetEffects),
+        (["api", "effects"], _) if is_set => match read_json::<StartEffectBody>(request) {
+            Ok(body) => call_api(
+                sender,
+                ApiRequest::StartEffect {
+                    ip,
+                    nick: body.nick,
+                    name: body.name,
+                    effect: body.effect,
+                    lights: body.lights,
+                },
+            ),
+            Err(reply) => reply,
+        },
+        (["api", "effects"], Method::Delete) => {
+            call_api(sender, ApiRequest::StopEffect { name: None })
+        }
+        (["api", "effects", name], Method::Delete) => call_api(
+            sender,
+            ApiRequest::StopEffect {
+                name: Some((*name).to_owned()),
+            },
+        ),
+        (["api", "scenes"], Method::Get) | (["api", "scene"], Method::Get) => {
+            call_api(sender, ApiRequest::GetScenes)
+        }
+        (["api", "scenes", name], _) if is_set => call_api(
+            sender,
+            ApiRequest::StoreScene {
+                name: (*name).to_owned(),
+            },
+        ),
+        (["api", "scenes", name], Method::Delete) => call_api(
+            sender,
+            ApiRequest::DeleteScene {
+                name: (*name).to_owned(),
+            },
+        ),
+        (["api", "scene"], _) if is_set => match read_json::<RecallSceneBody>(request) {
+            Ok(body) => match Duration::try_from_secs_f32(body.fade) {
+                Ok(fade) => call_api(sender, ApiRequest::RecallScene { name: body.name, fade }),
+                Err(_) => Reply::error(400, format!("Invalid fade time {}", body.fade)),
+            },
+            Err(reply) => reply,
+        },
+        (["api", "scene"], Method::Delete) => call_api(sender, ApiRequest::ReleaseScene),
+        (["api", "cues"], Method::Get) => call_api(sender, ApiRequest::GetCues),
+        (["api", "cues", action], _) if is_set => match *action {
+            "go" => call_api(sender, ApiRequest::GoCue),
+            "back" => call_api(sender, ApiRequest::BackCue),
+            "pause" => call_api(sender, ApiRequest::PauseCues),
+            "resume" => call_api(sender, ApiRequest::ResumeCues),
+            _ => Reply::error(404, format!("Unknown cue action {}", action)),
+        },
+        _ => Reply::error(404, format!("Not found: {}", path)),
+    }
+}
+
+/// Decode the `%XX` escapes of a URL path segment, or `None` if it has
+/// broken escapes or isn't UTF-8 once decoded.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Read a request body as JSON.
+fn read_json<T: serde::de::DeserializeOwned>(request: &mut Request) -> Result<T, Reply> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|err| Reply::error(400, format!("{:?}", err)))?;
+    serde_json::from_str(&body).map_err(|err| Reply::error(400, format!("{}", err)))
+}
+
+/// Pass a request to the server's event loop and wait for the reply.
+fn call_api(sender: &Sender<ServerMessage>, request: ApiRequest) -> Reply {
+    let (reply_sender, reply_receiver) = channel();
+    let message = ServerMessage::Api {
+        request,
+        reply: reply_sender,
+        replayed: false,
+    };
+    if sender.send(message).is_err() {
+        return Reply::error(503, "Server is shutting down".to_owned());
+    }
+    match reply_receiver.recv() {
+        Ok(response) => response_to_reply(response),
+        Err(_) => Reply::error(500, "No reply from the server".to_owned()),
+    }
+}
+
+fn response_to_reply(response: ApiResponse) -> Reply {
+    match response {
+        ApiResponse::Lights(lights) => Reply::json(200, &lights),
+        ApiResponse::Light(light) => Reply::json(200, &light),
+        ApiResponse::Masters(masters) => Reply::json(200, &masters),
+        ApiResponse::Effects(effects) => Reply::json(200, &effects),
+        ApiResponse::Scenes(scenes) => Reply::json(200, &scenes),
+        ApiResponse::Cues(cues) => Reply::json(200, &cues),
+        ApiResponse::Done => Reply::json(200, &serde_json::json!({ "ok": true })),
+        ApiResponse::NotFound(error) => Reply::error(404, error),
+        ApiResponse::Invalid(error) => Reply::error(400, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_segments() {
+        assert_eq!(percent_decode("stage").as_deref(), Some("stage"));
+        assert_eq!(
+            percent_decode("front%20wash").as_deref(),
+            Some("front wash")
+        );
+        assert_eq!(percent_decode("a%2fb%2F").as_deref(), Some("a/b/"));
+        assert_eq!(percent_decode("%C3%A4").as_deref(), Some("ä"));
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+}
@@ -1,55 +1,222 @@
 //! Accepts UDP and other things from the network.
 
+mod dmx;
+mod http;
+mod record;
+mod websocket;
+
 use std::io;
 use std::net::{IpAddr, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use crate::api::{self, ApiEvent, ApiRequest, ApiResponse};
 use crate::config::Root;
-use crate::mapper::Mapper;
+use crate::mapper::{Mapper, MapperError};
+
+use self::record::Recorder;
 
 const MAX_PACKET_SIZE: usize = 4096;
 
 /// Message formats that can be received by the server(s).
+///
+/// `replayed` tells messages from a recording apart from the ones received
+/// from the network.
 enum ServerMessage {
-    Binary { ip: IpAddr, data: Vec<u8> },
+    Binary {
+        ip: IpAddr,
+        data: Vec<u8>,
+        replayed: bool,
+    },
+    /// DMX channel data for the lights of a host.
+    Dmx {
+        ip: IpAddr,
+        protocol: &'static str,
+        host: String,
+        channels: Vec<u8>,
+        replayed: bool,
+    },
+    /// Time to send the current light state to the hosts.
+    Frame,
+    /// Request from one of the APIs. The response is sent to `reply`.
+    Api {
+        request: ApiRequest,
+        reply: Sender<ApiResponse>,
+        replayed: bool,
+    },
+    /// Start sending JSON encoded `ApiEvent`s to `events`.
+    Subscribe { events: Sender<String> },
+    /// Stop the server, letting the hosts shut down cleanly.
+    Shutdown,
+}
+
+impl ServerMessage {
+    fn is_replayed(&self) -> bool {
+        match self {
+            ServerMessage::Binary { replayed, .. }
+            | ServerMessage::Dmx { replayed, .. }
+            | ServerMessage::Api { replayed, .. } => *replayed,
+            _ => false,
+        }
+    }
 }
 
 /// Start an API for a pre-configured Mapper.
-pub fn serve(config: Root, mut mapper: Mapper) -> io::Result<()> {
+///
+/// With `replay`, the messages of a recording are fed to the
+/// Mapper along with the ones from the network.
+pub fn serve(config: Root, mut mapper: Mapper, replay: Option<&Path>) -> io::Result<()> {
     // Message channel used as the server's event bus.
     let (sender, receiver) = channel::<ServerMessage>();
 
-    // Start the server(s).
-    let udp_handle = start_udp_thread(&config.server.udp_addr, sender.clone());
+    // Shut down cleanly on Ctrl-C.
+    let shutdown_sender = sender.clone();
+    ctrlc::set_handler(move || {
+        let _ = shutdown_sender.send(ServerMessage::Shutdown);
+    })
+    .expect("Unable to set Ctrl-C handler!");
+
+    // Start the server(s). They block on their sockets, so they're left
+    // running when the server stops.
+    start_udp_thread(&config.server.udp_addr, sender.clone());
+    http::start_http_thread(&config, sender.clone());
+    websocket::start_websocket_thread(&config.server.websocket_addr, sender.clone());
+    if let Some(input) = &config.server.artnet_input {
+        dmx::start_artnet_thread(input.clone(), sender.clone());
+    }
+    if let Some(input) = &config.server.sacn_input {
+        dmx::start_sacn_thread(input.clone(), sender.clone());
+    }
+
+    if let Some(path) = replay {
+        record::start_replay_thread(path, sender.clone())?;
+    }
+    let mut recorder = match &config.server.record_dir {
+        Some(dir) => {
+            let (recorder, path) = Recorder::create(dir.as_ref())?;
+            println!("[record] Recording to {}", path.display());
+            Some(recorder)
+        }
+        None => None,
+    };
+
+    // Event streams of API clients.
+    let mut subscribers: Vec<Sender<String>> = vec![];
+
+    // Start the output scheduler.
+    let frame_pending = Arc::new(AtomicBool::new(false));
+    start_frame_thread(
+        config.server.frame_rate,
+        frame_pending.clone(),
+        sender.clone(),
+    );
 
     // Listen to messages from the server(s) and pass them to the mapper.
     'message_loop: loop {
-        match receiver.recv() {
-            Ok(request) => match request {
-                ServerMessage::Binary { ip, data } => {
-                    match mapper.take_msg(data.as_slice(), Some(ip)) {
-                        Ok(_) => {
-                            // ...
-                        }
-                        Err(err) => {
-                            eprintln!("msg fail: {:?}", err);
-                        }
-                    }
-                }
-            },
+        let request = match receiver.recv() {
+            Ok(request) => request,
             Err(err) => {
                 eprintln!("{:?}", err);
                 break 'message_loop;
             }
+        };
+        // Replayed messages are in a recording already.
+        if !request.is_replayed() {
+            record(&mut recorder, &request);
+        }
+        match request {
+            ServerMessage::Binary { ip, data, .. } => {
+                match mapper.take_msg(data.as_slice(), Some(ip)) {
+                    Ok(_) => {
+                        // ...
+                    }
+                    // Reordered packets are business as usual over Wi-Fi.
+                    Err(MapperError::StaleMessage(_)) => {}
+                    Err(err) => {
+                        eprintln!("msg fail: {:?}", err);
+                    }
+                }
+            }
+            ServerMessage::Dmx { ip, protocol, host, channels, .. } => {
+                if let Err(err) = mapper.take_dmx(&host, &channels, Some(ip), protocol) {
+                    eprintln!("dmx fail: {:?}", err);
+                }
+            }
+            ServerMessage::Frame => {
+                frame_pending.store(false, Ordering::SeqCst);
+                // Once a frame is enough to not lose much in a crash.
+                if let Some(active) = &mut recorder {
+                    if let Err(err) = active.flush() {
+                        eprintln!("record fail: {:?}", err);
+                        recorder = None;
+                    }
+                }
+                match mapper.render_frame() {
+                    Ok(_) => {}
+                    Err(MapperError::IoError(err)) => {
+                        eprintln!("host flush fail: {:?}", err);
+                    }
+                    Err(err) => {
+                        eprintln!("frame fail: {:?}", err);
+                    }
+                }
+                let lights = mapper.take_changed_lights();
+                if !lights.is_empty() {
+                    publish(&mut subscribers, &ApiEvent::Lights { lights });
+                }
+            }
+            ServerMessage::Api { request, reply, .. } => {
+                // The requester may have given up already, that's fine.
+                let _ = reply.send(api::handle(&mut mapper, request));
+            }
+            ServerMessage::Subscribe { events } => {
+                // Start with the current state of everything.
+                let mut subscriber = vec![events];
+                let lights = mapper.lights_info();
+                publish(&mut subscriber, &ApiEvent::Lights { lights });
+                subscribers.append(&mut subscriber);
+            }
+            ServerMessage::Shutdown => break 'message_loop,
         }
     }
 
-    udp_handle.join().expect("Did the UDP thread crash?");
-
+    println!("Shutting down.");
+    if let Some(active) = &mut recorder {
+        let _ = active.flush();
+    }
+    // Dropping the mapper lets the hosts shut down cleanly, like sACN
+    // hosts telling receivers that their streams have ended.
+    drop(mapper);
     Ok(())
 }
 
+/// Add a message to the recording, if there is one. The recording stops if
+/// the message can't be written.
+fn record(recorder: &mut Option<Recorder>, message: &ServerMessage) {
+    if let Some(active) = recorder {
+        if let Err(err) = active.record(message) {
+            eprintln!("record fail: {:?}", err);
+            *recorder = None;
+        }
+    }
+}
+
+/// Send an event to every subscriber, forgetting the ones that have gone away.
+fn publish(subscribers: &mut Vec<Sender<String>>, event: &ApiEvent) {
+    let event = match serde_json::to_string(event) {
+        Ok(event) => event,
+        Err(err) => {
+            eprintln!("Unable to serialize event: {:?}", err);
+            return;
+        }
+    };
+    subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
+
 /// Start a thread that will accept UDP packets and message them
 /// to the server's event loop.
 fn start_udp_thread(udp_addr: &str, sender: Sender<ServerMessage>) -> JoinHandle<()> {
@@ -66,6 +233,7 @@ fn start_udp_thread(udp_addr: &str, sender: Sender<ServerMessage>) -> JoinHandle
         let message = ServerMessage::Binary {
             ip: source.ip(),
             data: slice.to_owned(),
+            replayed: false,
         };
 
         sender
@@ -73,3 +241,39 @@ fn start_udp_thread(udp_addr: &str, sender: Sender<ServerMessage>) -> JoinHandle
             .expect("[udp] Packet receiver gone. Exiting thread.");
     })
 }
+
+/// Start a thread that will ask the server's event loop to render a frame
+/// at a fixed rate.
+///
+/// A new frame isn't requested while the previous one is still pending,
+/// so a slow host can't make the frames queue up.
+fn start_frame_thread(
+    frame_rate: f32,
+    frame_pending: Arc<AtomicBool>,
+    sender: Sender<ServerMessage>,
+) -> JoinHandle<()> {
+    println!("[frame] Sending frames at {} Hz", frame_rate);
+    let period = Duration::from_secs_f32(1.0 / frame_rate);
+
+    thread::spawn(move || {
+        let mut next_frame = Instant::now();
+        loop {
+            next_frame += period;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                // We've fallen behind, so don't try to catch up.
+                next_frame = now;
+            }
+
+            if frame_pending.swap(true, Ordering::SeqCst) {
+                continue;
+            }
+            if sender.send(ServerMessage::Frame).is_err() {
+                eprintln!("[frame] Frame receiver gone. Exiting thread.");
+                return;
+            }
+        }
+    })
+}
@@ -0,0 +1,427 @@
+//! Recording received protocol messages to a file and replaying them.
+//!
+//! A recording starts with a header: the bytes `ESREC`, a format version
+//! byte and the start time as big-endian Unix milliseconds (u64). Then for
+//! every message:
+//!
+//! - Milliseconds since the start (u32, big-endian)
+//! - Address family: 4 or 6, followed by the 4 or 16 byte source IP, or 0
+//!   for API requests, which have their addresses inside
+//! - Message type: 0 for a protocol message, 1 for Art-Net DMX data, 2 for
+//!   sACN DMX data or 3 for an API request
+//! - For a protocol message, its length (u32, big-endian) followed by the
+//!   message
+//! - For DMX data, the length of the host name (u16, big-endian) and the
+//!   name, then the number of channels (u16, big-endian) and the channels
+//! - For an API request, its length (u32, big-endian) followed by the
+//!   request as JSON
+//!
+//! API requests that only get something aren't recorded, and neither are
+//! replayed messages.
+
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::dmx::{ARTNET, SACN};
+use super::ServerMessage;
+
+const MAGIC: &[u8; 5] = b"ESREC";
+const VERSION: u8 = 2;
+
+const TYPE_BINARY: u8 = 0;
+/// DMX data types by protocol, starting from 1.
+const DMX_PROTOCOLS: [&str; 2] = [ARTNET, SACN];
+const TYPE_API: u8 = 3;
+
+/// Writes received messages to a recording file.
+pub(super) struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Start a new recording in a directory, named by the current time.
+    /// A recording started in the same second gets a number after the time.
+    pub fn create(dir: &Path) -> io::Result<(Recorder, PathBuf)> {
+        fs::create_dir_all(dir)?;
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut number = 0;
+        let (file, path) = loop {
+            let name = match number {
+                0 => format!("{}.rec", since_epoch.as_secs()),
+                _ => format!("{}-{}.rec", since_epoch.as_secs(), number),
+            };
+            let path = dir.join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (file, path),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => number += 1,
+                Err(err) => return Err(err),
+            }
+        };
+        let mut file = BufWriter::new(file);
+        file.write_all(MAGIC)?;
+        file.write_u8(VERSION)?;
+        file.write_u64::<BigEndian>(since_epoch.as_millis() as u64)?;
+        let recorder = Recorder {
+            file,
+            start: Instant::now(),
+        };
+        Ok((recorder, path))
+    }
+
+    /// Add a message to the recording. Messages other than protocol
+    /// messages, DMX data and API requests that change something are
+    /// left out.
+    pub fn record(&mut self, message: &ServerMessage) -> io::Result<()> {
+        let ip = match message {
+            ServerMessage::Binary { ip, .. } | ServerMessage::Dmx { ip, .. } => Some(*ip),
+            ServerMessage::Api { request, .. } if request.is_change() => None,
+            _ => return Ok(()),
+        };
+        let time = self.start.elapsed().as_millis() as u32;
+        self.file.write_u32::<BigEndian>(time)?;
+        match ip {
+            Some(IpAddr::V4(ip)) => {
+                self.file.write_u8(4)?;
+                self.file.write_all(&ip.octets())?;
+            }
+            Some(IpAddr::V6(ip)) => {
+                self.file.write_u8(6)?;
+                self.file.write_all(&ip.octets())?;
+            }
+            None => self.file.write_u8(0)?,
+        }
+        match message {
+            ServerMessage::Dmx {
+                protocol,
+                host,
+                channels,
+                ..
+            } => {
+                let index = DMX_PROTOCOLS
+                    .iter()
+                    .position(|known| known == protocol)
+                    .ok_or_else(|| invalid_input("Unknown DMX protocol"))?;
+                self.file.write_u8(index as u8 + 1)?;
+                self.write_u16_prefixed(host.as_bytes())?;
+                self.write_u16_prefixed(channels)
+            }
+            ServerMessage::Binary { data, .. } => {
+                let len =
+                    u32::try_from(data.len()).map_err(|_| invalid_input("Message too long"))?;
+                self.file.write_u8(TYPE_BINARY)?;
+                self.file.write_u32::<BigEndian>(len)?;
+                self.file.write_all(data)
+            }
+            ServerMessage::Api { request, .. } => {
+                let json = serde_json::to_vec(request).map_err(io::Error::other)?;
+                let len =
+                    u32::try_from(json.len()).map_err(|_| invalid_input("Request too long"))?;
+                self.file.write_u8(TYPE_API)?;
+                self.file.write_u32::<BigEndian>(len)?;
+                self.file.write_all(&json)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write_u16_prefixed(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = u16::try_from(data.len()).map_err(|_| invalid_input("DMX data too long"))?;
+        self.file.write_u16::<BigEndian>(len)?;
+        self.file.write_all(data)
+    }
+
+    /// Write out what has been recorded so far.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A recorded message.
+struct Record {
+    /// Time since the start of the recording.
+    time: Duration,
+    /// The message, marked as replayed.
+    message: ServerMessage,
+}
+
+/// Read and check the header of a recording.
+fn read_header(file: &mut dyn Read) -> io::Result<()> {
+    let mut magic = [0; 5];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("Not a recording".to_owned()));
+    }
+    let version = file.read_u8()?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "Unsupported recording version {}",
+            version
+        )));
+    }
+    // Start time of the recording, not needed for replaying.
+    file.read_u64::<BigEndian>()?;
+    Ok(())
+}
+
+/// Read the next message of a recording, or `None` at the end.
+fn read_record(file: &mut dyn Read) -> io::Result<Option<Record>> {
+    let time = match file.read_u32::<BigEndian>() {
+        Ok(time) => Duration::from_millis(u64::from(time)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let ip = match file.read_u8()? {
+        0 => None,
+        4 => {
+            let mut octets = [0; 4];
+            file.read_exact(&mut octets)?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        6 => {
+            let mut octets = [0; 16];
+            file.read_exact(&mut octets)?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        family => return Err(invalid_data(format!("Invalid address family {}", family))),
+    };
+    let message_type = file.read_u8()?;
+    if message_type == TYPE_API {
+        let request = serde_json::from_slice(&read_u32_prefixed(file)?)
+            .map_err(|err| invalid_data(format!("Invalid API request: {}", err)))?;
+        // Nobody is waiting for the response.
+        let (reply, _) = channel();
+        let message = ServerMessage::Api {
+            request,
+            reply,
+            replayed: true,
+        };
+        return Ok(Some(Record { time, message }));
+    }
+    let ip = ip.ok_or_else(|| invalid_data("Missing source address".to_owned()))?;
+    let message = match message_type {
+        TYPE_BINARY => ServerMessage::Binary {
+            ip,
+            data: read_u32_prefixed(file)?,
+            replayed: true,
+        },
+        message_type => {
+            let protocol = usize::from(message_type)
+                .checked_sub(1)
+                .and_then(|index| DMX_PROTOCOLS.get(index))
+                .ok_or_else(|| invalid_data(format!("Invalid message type {}", message_type)))?;
+            let host = String::from_utf8(read_u16_prefixed(file)?)
+                .map_err(|_| invalid_data("Invalid host name".to_owned()))?;
+            ServerMessage::Dmx {
+                ip,
+                protocol,
+                host,
+                channels: read_u16_prefixed(file)?,
+                replayed: true,
+            }
+        }
+    };
+    Ok(Some(Record { time, message }))
+}
+
+fn read_u32_prefixed(file: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let len = file.read_u32::<BigEndian>()?;
+    let mut data = vec![];
+    file.take(u64::from(len)).read_to_end(&mut data)?;
+    if data.len() != len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(data)
+}
+
+fn read_u16_prefixed(file: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; usize::from(file.read_u16::<BigEndian>()?)];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Start a thread that will send the messages of a recording to the
+/// server's event loop with their original timing.
+pub(super) fn start_replay_thread(
+    path: &Path,
+    sender: Sender<ServerMessage>,
+) -> io::Result<JoinHandle<()>> {
+    let mut file = BufReader::new(File::open(path)?);
+    read_header(&mut file).map_err(|err| {
+        eprintln!("[replay] Unable to replay {}: {}", path.display(), err);
+        err
+    })?;
+
+    println!("[replay] Replaying {}", path.display());
+    Ok(thread::spawn(move || {
+        let start = Instant::now();
+        loop {
+            let record = match read_record(&mut file) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(err) => {
+                    eprintln!("[replay] Unable to read recording: {:?}", err);
+                    break;
+                }
+            };
+            let at = start + record.time;
+            let now = Instant::now();
+            if at > now {
+                thread::sleep(at - now);
+            }
+            if sender.send(record.message).is_err() {
+                eprintln!("[replay] Packet receiver gone. Exiting thread.");
+                return;
+            }
+        }
+        println!("[replay] Done.");
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{ApiRequest, LightUpdate};
+    use crate::mapper::LightChange;
+
+    fn api(request: ApiRequest) -> ServerMessage {
+        ServerMessage::Api {
+            request,
+            reply: channel().0,
+            replayed: false,
+        }
+    }
+
+    #[test]
+    fn record_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("record-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (mut recorder, path) = Recorder::create(&dir).unwrap();
+        // Another recording in the same second doesn't overwrite the first.
+        let (_, other_path) = Recorder::create(&dir).unwrap();
+        assert_ne!(path, other_path);
+
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        let v6: IpAddr = "fe80::1".parse().unwrap();
+        let messages = vec![
+            ServerMessage::Binary {
+                ip: v4,
+                data: vec![1, 1, 0, 0, 255, 0, 0],
+                replayed: false,
+            },
+            ServerMessage::Frame,
+            ServerMessage::Dmx {
+                ip: v6,
+                protocol: SACN,
+                host: "enttec".to_owned(),
+                channels: vec![0, 128, 255],
+                replayed: false,
+            },
+            ServerMessage::Binary {
+                ip: v6,
+                data: vec![0; 70_000],
+                replayed: false,
+            },
+            api(ApiRequest::GetLights),
+            api(ApiRequest::SetLights {
+                ip: Some(v4),
+                nick: Some("panel".to_owned()),
+                lights: vec![LightUpdate {
+                    id: Some(3),
+                    change: LightChange::rgb(255, 0, 128),
+                }],
+            }),
+            api(ApiRequest::RecallScene {
+                name: "blue".to_owned(),
+                fade: Duration::from_millis(2500),
+            }),
+        ];
+        for message in &messages {
+            recorder.record(message).unwrap();
+        }
+        recorder.flush().unwrap();
+        drop(recorder);
+
+        let mut file = BufReader::new(File::open(&path).unwrap());
+        read_header(&mut file).unwrap();
+        let mut read = vec![];
+        while let Some(record) = read_record(&mut file).unwrap() {
+            assert!(record.time < Duration::from_secs(10));
+            read.push(record.message);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(read.len(), 5);
+        match &read[0] {
+            ServerMessage::Binary { ip, data, replayed } => {
+                assert_eq!(
+                    (*ip, data.as_slice(), *replayed),
+                    (v4, &[1, 1, 0, 0, 255, 0, 0][..], true)
+                );
+            }
+            _ => panic!("Expected a protocol message"),
+        }
+        match &read[1] {
+            ServerMessage::Dmx {
+                ip,
+                protocol,
+                host,
+                channels,
+                replayed,
+            } => {
+                assert_eq!((*ip, *protocol, host.as_str()), (v6, SACN, "enttec"));
+                assert_eq!((channels.as_slice(), *replayed), (&[0, 128, 255][..], true));
+            }
+            _ => panic!("Expected DMX data"),
+        }
+        match &read[2] {
+            ServerMessage::Binary { data, .. } => assert_eq!(data.len(), 70_000),
+            _ => panic!("Expected a protocol message"),
+        }
+        // Getting the lights didn't change anything, so it wasn't recorded.
+        for (read, written) in read[3..].iter().zip(&messages[5..]) {
+            match (read, written) {
+                (
+                    ServerMessage::Api {
+                        request, replayed, ..
+                    },
+                    ServerMessage::Api {
+                        request: written, ..
+                    },
+                ) => {
+                    assert_eq!(format!("{:?}", request), format!("{:?}", written));
+                    assert!(replayed);
+                }
+                _ => panic!("Expected an API request"),
+            }
+        }
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut header = MAGIC.to_vec();
+        header.push(1);
+        header.extend_from_slice(&[0; 8]);
+        let err = read_header(&mut header.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read_header(&mut &b"hello world, hi!"[..]).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! WebSocket control and state stream.
+//!
+//! Clients can send the same commands as over UDP, either as binary v1
+//! messages or as JSON text messages like
+//! `{"nick": "...", "lights": [{"id": 0, "red": 255, "green": 0, "blue": 0}]}`.
+//!
+//! Every client is subscribed to light state changes, which are pushed as
+//! JSON text messages like `{"event": "lights", "lights": [...]}`.
+
+use std::io;
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use tungstenite::{Error, Message, WebSocket};
+
+use super::ServerMessage;
+use crate::api::{ApiRequest, ApiResponse, SetLightsBody};
+
+/// Result type for WebSocket connections. tungstenite's errors are on the large side.
+type WsResult<T> = Result<T, Box<Error>>;
+
+/// How long to wait for client messages before checking for events to push.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Start a thread that accepts WebSocket connections and starts
+/// a thread for each of them.
+pub(super) fn start_websocket_thread(
+    websocket_addr: &str,
+    sender: Sender<ServerMessage>,
+) -> JoinHandle<()> {
+    println!("[ws] Starting WebSocket server at {}", websocket_addr);
+    let listener =
+        TcpListener::bind(websocket_addr).expect("[ws] Unable to create WebSocket server!");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = handle_connection(stream, sender) {
+                            eprintln!("[ws] Connection closed: {:?}", err);
+                        }
+                    });
+                }
+                Err(err) => eprintln!("[ws] Unable to accept connection: {:?}", err),
+            }
+        }
+    })
+}
+
+/// Serve a single WebSocket client until it disconnects.
+fn handle_connection(stream: TcpStream, sender: Sender<ServerMessage>) -> WsResult<()> {
+    let ip = stream.peer_addr().map_err(Error::Io)?.ip();
+    let mut websocket = tungstenite::accept(stream).map_err(|err| match err {
+        tungstenite::HandshakeError::Failure(err) => err,
+        tungstenite::HandshakeError::Interrupted(_) => {
+            Error::Io(io::Error::from(io::ErrorKind::WouldBlock))
+        }
+    })?;
+    // Time out reads so that events can be pushed while the client is quiet.
+    websocket
+        .get_ref()
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(Error::Io)?;
+    println!("[ws] Client connected from {}", ip);
+
+    let (event_sender, events) = channel();
+    if sender.send(ServerMessage::Subscribe { events: event_sender }).is_err() {
+        return Ok(());
+    }
+
+    loop {
+        match websocket.read() {
+            Ok(message) => {
+                if !take_message(&mut websocket, message, ip, &sender)? {
+                    return Ok(());
+                }
+            }
+            Err(Error::Io(ref err))
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut => {}
+            Err(Error::ConnectionClosed) => return Ok(()),
+            Err(err) => return Err(Box::new(err)),
+        }
+        push_events(&mut websocket, &events)?;
+    }
+}
+
+/// Handle a message from a client. Returns Ok(false) if the server is gone.
+fn take_message(
+    websocket: &mut WebSocket<TcpStream>,
+    message: Message,
+    ip: IpAddr,
+    sender: &Sender<ServerMessage>,
+) -> WsResult<bool> {
+    match message {
+        Message::Binary(data) => {
+            let message = ServerMessage::Binary {
+                ip,
+                data,
+                replayed: false,
+            };
+            Ok(sender.send(message).is_ok())
+        }
+        Message::Text(text) => {
+            let body: SetLightsBody = match serde_json::from_str(&text) {
+                Ok(body) => body,
+                Err(err) => {
+                    send_error(websocket, format!("{}", err))?;
+                    return Ok(true);
+                }
+            };
+            let (reply_sender, reply_receiver) = channel();
+            let message = ServerMessage::Api {
+                request: ApiRequest::SetLights {
+                    ip: Some(ip),
+                    nick: body.nick,
+                    lights: body.lights,
+                },
+                reply: reply_sender,
+                replayed: false,
+            };
+            if sender.send(message).is_err() {
+                return Ok(false);
+            }
+            match reply_receiver.recv() {
+                Ok(ApiResponse::NotFound(error)) | Ok(ApiResponse::Invalid(error)) => {
+                    send_error(websocket, error)?;
+                }
+                Ok(_) => {}
+                Err(_) => return Ok(false),
+            }
+            Ok(true)
+        }
+        // Pings are answered by tungstenite and closes end the read loop.
+        _ => Ok(true),
+    }
+}
+
+/// Tell the client that its message didn't work out.
+fn send_error(websocket: &mut WebSocket<TcpStream>, error: String) -> WsResult<()> {
+    let body = serde_json::json!({ "event": "error", "error": error });
+    Ok(websocket.send(Message::Text(body.to_string()))?)
+}
+
+/// Send any pending events to the client.
+fn push_events(websocket: &mut WebSocket<TcpStream>, events: &Receiver<String>) -> WsResult<()> {
+    loop {
+        match events.try_recv() {
+            Ok(event) => websocket.send(Message::Text(event))?,
+            Err(TryRecvError::Empty) => return Ok(()),
+            Err(TryRecvError::Disconnected) => return Err(Box::new(Error::ConnectionClosed)),
+        }
+    }
+}